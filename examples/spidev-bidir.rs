@@ -3,7 +3,7 @@ use spidev::spidevioctl::SpidevTransfer;
 use spidev::{SpiModeFlags, Spidev, SpidevOptions};
 
 fn main() {
-    let mut spidev = Spidev::open("/dev/spidev0.0").unwrap();
+    let mut spidev = Spidev::open_default().unwrap();
     let options = SpidevOptions::new()
         .bits_per_word(8)
         .max_speed_hz(5000)
@@ -42,4 +42,22 @@ fn main() {
         }
         Err(err) => println!("{:?}", err),
     }
+
+    println!("===== Command / Deselect / Command =========");
+    let write_enable = [0x06];
+    let read_status = [0x05];
+    let mut status = [0; 1];
+    let result = {
+        let mut transfers = vec![
+            SpidevTransfer::write(&write_enable),
+            SpidevTransfer::cs_toggle(),
+            SpidevTransfer::write(&read_status),
+            SpidevTransfer::read(&mut status),
+        ];
+        spidev.transfer_multiple(&mut transfers)
+    };
+    match result {
+        Ok(_) => println!("Status after write enable {:?}", status),
+        Err(err) => println!("{:?}", err),
+    }
 }
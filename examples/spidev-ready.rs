@@ -0,0 +1,35 @@
+extern crate spidev;
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use std::time::Duration;
+
+fn main() {
+    let mut spidev = Spidev::open_default().unwrap();
+    let options = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(1_000_000)
+        .mode(SpiModeFlags::SPI_MODE_0)
+        .build();
+    spidev.configure(&options).unwrap();
+
+    // Let the device pause the transfer by pulling its ready line low.
+    // Controllers without support for the handshake reject this.
+    match spidev.set_ready_handshake(true) {
+        Ok(()) => println!("SPI_READY enabled: {:?}", spidev.ready_handshake()),
+        Err(err) => {
+            println!("Controller does not support SPI_READY: {}", err);
+            return;
+        }
+    }
+
+    // A device that never becomes ready would block the transfer
+    // forever, so bound it.
+    let tx_buf = [0x01, 0x02, 0x03, 0x04];
+    let mut rx_buf = [0; 4];
+    let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+    match spidev.transfer_timeout(&mut transfer, Duration::from_millis(500)) {
+        Ok(()) => println!("Read {:?}", rx_buf),
+        Err(err) => println!("Transfer failed: {}", err),
+    }
+
+    spidev.set_ready_handshake(false).unwrap();
+}
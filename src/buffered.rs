@@ -0,0 +1,153 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{SpiDevice, Spidev, SpidevTransfer};
+use std::io;
+use std::io::prelude::*;
+
+/// Coalesces small writes into transfers of up to `bufsiz` bytes
+///
+/// This is the spidev counterpart of [`io::BufWriter`]: data written
+/// is collected until the buffer would overflow, `flush` is called or
+/// the writer is dropped, and then sent as a single write transfer.
+/// Protocols which push many small messages thereby need far fewer
+/// system calls.  Unlike `write()` on the device file, each flush is
+/// one transfer, so the device sees the buffered data as one frame
+/// with the chip selected throughout.
+///
+/// Writes at least as large as the buffer bypass it and are sent as
+/// transfers of at most the buffer's capacity.  Errors while flushing
+/// on drop are ignored; call `flush` to observe them.
+#[derive(Debug)]
+pub struct BufferedSpidev<'a, S: SpiDevice = Spidev> {
+    spi: &'a S,
+    buf: Vec<u8>,
+    capacity: usize,
+}
+
+impl<'a> BufferedSpidev<'a> {
+    /// Buffer writes to `spidev` in transfers of up to `bufsiz` bytes
+    pub fn new(spidev: &'a Spidev) -> Self {
+        BufferedSpidev::with_capacity(spidev, spidev.bufsiz())
+    }
+}
+
+impl<'a, S: SpiDevice> BufferedSpidev<'a, S> {
+    /// Buffer writes to `spi` in transfers of up to `capacity` bytes
+    ///
+    /// A capacity above the `bufsiz` of the spidev module makes large
+    /// flushes fail.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_capacity(spi: &'a S, capacity: usize) -> Self {
+        assert!(capacity > 0, "buffer capacity must not be zero");
+        BufferedSpidev {
+            spi,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// The device written to
+    pub fn get_ref(&self) -> &'a S {
+        self.spi
+    }
+
+    /// The data written but not sent yet
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// The largest number of bytes sent in one transfer
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Send `data` as a single write transfer
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.spi.transfer(&mut SpidevTransfer::write(data))
+    }
+
+    /// Send the buffered data, if any
+    fn flush_buf(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            // The data is dropped even on failure, as with a write()
+            let result = self.send(&self.buf);
+            self.buf.clear();
+            result?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: SpiDevice> Write for BufferedSpidev<'a, S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.capacity {
+            self.flush_buf()?;
+        }
+        if data.len() >= self.capacity {
+            let frame = &data[..self.capacity];
+            self.send(frame)?;
+            Ok(frame.len())
+        } else {
+            self.buf.extend_from_slice(data);
+            Ok(data.len())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()
+    }
+}
+
+impl<'a, S: SpiDevice> Drop for BufferedSpidev<'a, S> {
+    fn drop(&mut self) {
+        let _ = self.flush_buf();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockSpidev;
+
+    #[test]
+    fn test_coalesces_writes() {
+        let spi = MockSpidev::new();
+        let mut writer = BufferedSpidev::with_capacity(&spi, 4);
+        writer.write_all(&[1, 2]).unwrap();
+        writer.write_all(&[3]).unwrap();
+        assert!(spi.written().is_empty());
+        assert_eq!(writer.buffer(), &[1, 2, 3]);
+
+        // Does not fit, so the buffered bytes go out first
+        writer.write_all(&[4, 5]).unwrap();
+        assert_eq!(spi.take_written(), vec![vec![1, 2, 3]]);
+        writer.flush().unwrap();
+        assert_eq!(spi.take_written(), vec![vec![4, 5]]);
+
+        // A large write sends a full frame directly, buffering the rest
+        writer.write_all(&[6, 7, 8, 9, 10, 11]).unwrap();
+        assert_eq!(spi.take_written(), vec![vec![6, 7, 8, 9]]);
+        assert_eq!(writer.buffer(), &[10, 11]);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let spi = MockSpidev::new();
+        {
+            let mut writer = BufferedSpidev::with_capacity(&spi, 16);
+            writer.write_all(b"abc").unwrap();
+            writer.write_all(b"def").unwrap();
+            assert!(spi.written().is_empty());
+        }
+        assert_eq!(spi.written(), vec![b"abcdef".to_vec()]);
+    }
+}
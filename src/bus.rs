@@ -0,0 +1,150 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Spidev, SpidevOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Serializes access to several devices sharing one bus
+///
+/// Each device is registered with the configuration it needs.  A
+/// transaction takes the bus lock, applies the configuration of its
+/// device and only then runs, so devices behind a mux or with
+/// different modes and speeds never see each other's settings.
+///
+/// ```no_run
+/// # use spidev::{SpiBusManager, SpidevOptions, SpiModeFlags};
+/// # fn main() -> std::io::Result<()> {
+/// let mut bus = SpiBusManager::new();
+/// let flash = bus.add_device(
+///     "/dev/spidev0.0",
+///     SpidevOptions::new().max_speed_hz(20_000_000).build(),
+/// )?;
+/// let adc = bus.add_device(
+///     "/dev/spidev0.1",
+///     SpidevOptions::new()
+///         .max_speed_hz(1_000_000)
+///         .mode(SpiModeFlags::SPI_MODE_1)
+///         .build(),
+/// )?;
+///
+/// let mut id = [0u8; 3];
+/// bus.transaction(flash, |spi| spi.write_read(&[0x9f], &mut id))?;
+/// let mut sample = [0u8; 2];
+/// bus.transaction(adc, |spi| spi.write_read(&[0x80], &mut sample))?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The lock only covers transactions run through the manager; other
+/// handles to the same devices are not serialized.
+#[derive(Debug, Default)]
+pub struct SpiBusManager {
+    devices: Vec<(Spidev, SpidevOptions)>,
+    lock: Mutex<()>,
+}
+
+impl SpiBusManager {
+    /// Create a manager without any devices
+    pub fn new() -> Self {
+        SpiBusManager::default()
+    }
+
+    /// Open the device at `path` and register it with `options`
+    ///
+    /// The options are checked with `SpidevOptions::validate`, failing
+    /// with the first problem found, but not written until the first
+    /// transaction.  The returned id identifies the device in
+    /// `transaction`; ids are assigned in order, starting at zero.
+    pub fn add_device<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        options: SpidevOptions,
+    ) -> io::Result<usize> {
+        if let Err(errors) = options.validate() {
+            return Err(errors[0].into());
+        }
+        let spidev = Spidev::open(path)?;
+        self.devices.push((spidev, options));
+        Ok(self.devices.len() - 1)
+    }
+
+    /// Number of registered devices
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Whether no device has been registered
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Configure device `device_id` and run `f` on it, holding the bus
+    ///
+    /// Other transactions wait until `f` returns.  If writing the
+    /// configuration fails, `f` is not run and the error is returned.
+    /// An unknown id fails with an error of kind `InvalidInput`.
+    pub fn transaction<T, F>(&self, device_id: usize, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&Spidev) -> io::Result<T>,
+    {
+        let (spidev, options) = self.devices.get(device_id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no SPI device with id {}", device_id),
+            )
+        })?;
+        // The bus stays usable even if a transaction panicked
+        let _bus = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        spidev.apply(options)?;
+        f(spidev)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transaction() {
+        let mut bus = SpiBusManager::new();
+        assert!(bus.is_empty());
+        // Empty options need no ioctls, which /dev/null would reject
+        let plain = bus.add_device("/dev/null", SpidevOptions::new()).unwrap();
+        let fast = bus
+            .add_device(
+                "/dev/null",
+                SpidevOptions::new().max_speed_hz(1_000_000).build(),
+            )
+            .unwrap();
+        assert_eq!((plain, fast, bus.len()), (0, 1, 2));
+
+        assert_eq!(bus.transaction(plain, |_| Ok(42)).unwrap(), 42);
+        let mut ran = false;
+        assert!(bus
+            .transaction(fast, |_| {
+                ran = true;
+                Ok(())
+            })
+            .is_err());
+        assert!(!ran);
+        assert_eq!(
+            bus.transaction(2, |_| Ok(())).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_add_device_invalid_options() {
+        let mut bus = SpiBusManager::new();
+        let options = SpidevOptions::new().max_speed_hz(0).build();
+        assert!(bus.add_device("/dev/null", options).is_err());
+        assert!(bus.is_empty());
+    }
+}
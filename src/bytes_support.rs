@@ -0,0 +1,44 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Transfers over the buffers of the `bytes` crate
+
+use super::{Spidev, SpidevTransfer};
+use bytes::{Bytes, BytesMut};
+use std::io;
+
+impl Spidev {
+    /// Perform a full-duplex transfer between `Bytes` buffers
+    ///
+    /// The transfer points directly at the memory of `tx` and `rx`, so
+    /// no intermediate `Vec` is needed when bridging SPI to code built
+    /// around `bytes`.  `rx` is resized to `tx.len()` first, which
+    /// only allocates if its capacity is too small; reusing one
+    /// `BytesMut` across calls avoids allocating altogether.
+    pub fn transfer_bytes(&self, tx: &Bytes, rx: &mut BytesMut) -> io::Result<()> {
+        rx.resize(tx.len(), 0);
+        let mut transfers = [SpidevTransfer::read_write(tx, rx)];
+        self.finish_message(&mut transfers);
+        self.submit_message(&mut transfers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transfer_bytes_resizes() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        let tx = Bytes::from_static(&[1, 2, 3]);
+        let mut rx = BytesMut::from(&[9u8; 8][..]);
+        // /dev/null rejects the transfer, but only after rx is sized
+        assert!(spi.transfer_bytes(&tx, &mut rx).is_err());
+        assert_eq!(rx.len(), 3);
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::spidevioctl::{self, SpidevTransfer};
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::prelude::*;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The device file of a `Spidev`, optionally deselecting it on drop
+#[derive(Debug)]
+pub(crate) struct DevFile {
+    file: File,
+    reset_on_drop: AtomicBool,
+}
+
+impl DevFile {
+    pub(crate) fn new(file: File) -> DevFile {
+        DevFile {
+            file,
+            reset_on_drop: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn reset_on_drop(&self) -> bool {
+        self.reset_on_drop.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_reset_on_drop(&self, reset: bool) {
+        self.reset_on_drop.store(reset, Ordering::Relaxed);
+    }
+
+    /// Duplicate the file; the copy does not reset on drop
+    pub(crate) fn try_clone(&self) -> io::Result<DevFile> {
+        Ok(DevFile::new(self.file.try_clone()?))
+    }
+
+    /// Take the file out without resetting the device
+    pub(crate) fn into_file(self) -> File {
+        let this = ManuallyDrop::new(self);
+        // The file is moved out exactly once and the flag has no drop
+        // glue, so nothing is leaked or dropped twice
+        unsafe { ptr::read(&this.file) }
+    }
+}
+
+impl Drop for DevFile {
+    fn drop(&mut self) {
+        if !self.reset_on_drop() {
+            return;
+        }
+        // An empty message ending with cs_change cleared leaves the
+        // chip deselected.  There is no way to report a failure here.
+        let mut transfers = [SpidevTransfer::default()];
+        let result = spidevioctl::retry_on_eintr(|| {
+            spidevioctl::transfer_multiple(self.file.as_raw_fd(), &mut transfers)
+        });
+        #[cfg(feature = "log")]
+        if let Err(err) = result {
+            log::debug!("failed to deselect spidev on drop: {}", err);
+        }
+        #[cfg(not(feature = "log"))]
+        let _ = result;
+    }
+}
+
+impl Deref for DevFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for DevFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::prelude::*;
+
+    #[test]
+    fn test_reset_on_drop_ignores_errors() {
+        // /dev/null rejects the transfer ioctl
+        let devfile = DevFile::new(File::open("/dev/null").unwrap());
+        devfile.set_reset_on_drop(true);
+        assert!(!devfile.try_clone().unwrap().reset_on_drop());
+        drop(devfile);
+    }
+
+    #[test]
+    fn test_into_file() {
+        let devfile = DevFile::new(File::open("/dev/null").unwrap());
+        devfile.set_reset_on_drop(true);
+        let mut file = devfile.into_file();
+        assert_eq!(file.read(&mut [0; 4]).unwrap(), 0);
+    }
+}
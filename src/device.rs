@@ -0,0 +1,75 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Spidev, SpidevOptions, SpidevTransfer};
+use std::io;
+
+/// The operations of a spidev device, for writing generic drivers
+///
+/// Peripheral drivers written against this trait, e.g. as
+/// `fn new<S: SpiDevice>(spi: S)`, work with a real [`Spidev`] and
+/// can be tested against the `MockSpidev` provided by the `mock`
+/// feature.
+pub trait SpiDevice {
+    /// Perform a single transfer
+    fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()>;
+
+    /// Perform multiple transfers as a single message
+    fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()>;
+
+    /// Write `tx` and then read into `rx` within a single message
+    fn write_read(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()>;
+
+    /// Write the provided configuration to the device
+    fn configure(&mut self, options: &SpidevOptions) -> io::Result<()>;
+
+    /// Read the current configuration from the device
+    fn query_configuration(&self) -> io::Result<SpidevOptions>;
+}
+
+impl SpiDevice for Spidev {
+    fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        Spidev::transfer(self, transfer)
+    }
+
+    fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        Spidev::transfer_multiple(self, transfers)
+    }
+
+    fn write_read(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        Spidev::write_read(self, tx, rx)
+    }
+
+    fn configure(&mut self, options: &SpidevOptions) -> io::Result<()> {
+        Spidev::configure(self, options)
+    }
+
+    fn query_configuration(&self) -> io::Result<SpidevOptions> {
+        Spidev::query_configuration(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockSpidev;
+
+    fn read_id<S: SpiDevice>(spi: &S) -> io::Result<[u8; 2]> {
+        let mut id = [0; 2];
+        spi.write_read(&[0x90], &mut id)?;
+        Ok(id)
+    }
+
+    #[test]
+    fn test_generic_driver_with_mock() {
+        let spi = MockSpidev::new();
+        spi.push_response(&[0x12, 0x34]);
+        assert_eq!(read_id(&spi).unwrap(), [0x12, 0x34]);
+        assert_eq!(spi.written(), vec![vec![0x90]]);
+    }
+}
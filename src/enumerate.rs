@@ -0,0 +1,159 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Description of a spidev device node found on the system
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpidevInfo {
+    /// Path of the device node, e.g. `/dev/spidev0.1`
+    pub path: PathBuf,
+    /// Number of the bus (controller) the device is attached to
+    pub bus: u32,
+    /// Chip select of the device on its bus
+    pub chip_select: u32,
+}
+
+/// Description of an SPI controller (bus) and its spidev devices
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpiControllerInfo {
+    /// Name of the device providing the controller, e.g.
+    /// `fe204000.spi`, or of the controller itself (`spi0`) if the
+    /// former is unknown
+    pub name: String,
+    /// Number of the bus
+    pub bus: u32,
+    /// spidev nodes on this bus, sorted by chip select
+    pub devices: Vec<SpidevInfo>,
+}
+
+/// Parse the bus and chip select out of a `spidevB.C` node name
+fn parse_node_name(name: &str) -> Option<(u32, u32)> {
+    let numbers = name.strip_prefix("spidev")?;
+    let (bus, chip_select) = numbers.split_once('.')?;
+    Some((bus.parse().ok()?, chip_select.parse().ok()?))
+}
+
+/// List the spidev nodes in `dir`, sorted by bus and chip select
+pub(crate) fn list_in(dir: &Path) -> io::Result<Vec<SpidevInfo>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if let Some((bus, chip_select)) = name.to_str().and_then(parse_node_name) {
+            devices.push(SpidevInfo {
+                path: entry.path(),
+                bus,
+                chip_select,
+            });
+        }
+    }
+    devices.sort_by_key(|info| (info.bus, info.chip_select));
+    Ok(devices)
+}
+
+/// List the controllers in the `spi_master` class directory `class_dir`
+///
+/// Their devices are looked up among the spidev nodes in `dev_dir`.
+pub(crate) fn controllers_in(
+    class_dir: &Path,
+    dev_dir: &Path,
+) -> io::Result<Vec<SpiControllerInfo>> {
+    let devices = list_in(dev_dir)?;
+    let mut controllers = Vec::new();
+    for entry in fs::read_dir(class_dir)? {
+        let entry = entry?;
+        let class_name = entry.file_name().to_string_lossy().into_owned();
+        let bus = match class_name.strip_prefix("spi").and_then(|n| n.parse().ok()) {
+            Some(bus) => bus,
+            None => continue,
+        };
+        let name = fs::canonicalize(entry.path().join("device"))
+            .ok()
+            .and_then(|parent| Some(parent.file_name()?.to_string_lossy().into_owned()))
+            .unwrap_or(class_name);
+        controllers.push(SpiControllerInfo {
+            name,
+            bus,
+            devices: devices
+                .iter()
+                .filter(|info| info.bus == bus)
+                .cloned()
+                .collect(),
+        });
+    }
+    controllers.sort_by_key(|controller| controller.bus);
+    Ok(controllers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_parse_node_name() {
+        assert_eq!(parse_node_name("spidev0.0"), Some((0, 0)));
+        assert_eq!(parse_node_name("spidev12.3"), Some((12, 3)));
+        assert_eq!(parse_node_name("spidev0"), None);
+        assert_eq!(parse_node_name("spidevx.1"), None);
+        assert_eq!(parse_node_name("ttyS0"), None);
+    }
+
+    #[test]
+    fn test_list_sorted() {
+        let dir = scratch_dir("list");
+        for name in &["spidev10.0", "spidev2.1", "spidev2.0", "null", "spidev"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let devices = list_in(&dir).unwrap();
+        let numbers: Vec<_> = devices
+            .iter()
+            .map(|info| (info.bus, info.chip_select))
+            .collect();
+        assert_eq!(numbers, vec![(2, 0), (2, 1), (10, 0)]);
+        assert_eq!(devices[0].path, dir.join("spidev2.0"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_controllers() {
+        let root = scratch_dir("controllers");
+        let class_dir = root.join("class");
+        let dev_dir = root.join("dev");
+        fs::create_dir_all(root.join("devices/fe204000.spi")).unwrap();
+        fs::create_dir_all(class_dir.join("spi1")).unwrap();
+        fs::create_dir_all(class_dir.join("spi0")).unwrap();
+        std::os::unix::fs::symlink(
+            root.join("devices/fe204000.spi"),
+            class_dir.join("spi0/device"),
+        )
+        .unwrap();
+        fs::create_dir_all(&dev_dir).unwrap();
+        for name in &["spidev0.1", "spidev0.0", "spidev2.0"] {
+            fs::write(dev_dir.join(name), b"").unwrap();
+        }
+
+        let controllers = controllers_in(&class_dir, &dev_dir).unwrap();
+        assert_eq!(controllers.len(), 2);
+        assert_eq!(controllers[0].name, "fe204000.spi");
+        assert_eq!(controllers[0].bus, 0);
+        let chip_selects: Vec<_> = controllers[0]
+            .devices
+            .iter()
+            .map(|info| info.chip_select)
+            .collect();
+        assert_eq!(chip_selects, vec![0, 1]);
+        assert_eq!(controllers[1].name, "spi1");
+        assert!(controllers[1].devices.is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Error building a transfer from invalid parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// The transmit and receive buffers of a full-duplex transfer
+    /// differ in length
+    LengthMismatch { tx_len: usize, rx_len: usize },
+    /// A delay does not fit the 16-bit microsecond field of a transfer
+    DelayTooLong { delay: Duration },
+    /// A bus width other than 1, 2, 4 or 8 wires was requested
+    InvalidNbits { nbits: u8 },
+    /// The length of a transfer is not a whole number of words
+    MisalignedLength { len: usize, bits_per_word: u8 },
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::LengthMismatch { tx_len, rx_len } => write!(
+                f,
+                "tx buffer length ({}) does not match rx buffer length ({})",
+                tx_len, rx_len
+            ),
+            TransferError::DelayTooLong { delay } => write!(
+                f,
+                "delay of {:?} exceeds the maximum of {} microseconds",
+                delay,
+                u16::MAX
+            ),
+            TransferError::InvalidNbits { nbits } => {
+                write!(f, "bus width of {} wires is not 1, 2, 4 or 8", nbits)
+            }
+            TransferError::MisalignedLength { len, bits_per_word } => write!(
+                f,
+                "transfer of {} bytes is not a whole number of {}-bit words",
+                len, bits_per_word
+            ),
+        }
+    }
+}
+
+impl Error for TransferError {}
+
+impl From<TransferError> for io::Error {
+    fn from(err: TransferError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// A raw SPI mode contains bits without a `SpiModeFlags` constant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownModeBits {
+    /// The unknown bits
+    pub bits: u32,
+}
+
+impl fmt::Display for UnknownModeBits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown SPI mode bits {:#x}", self.bits)
+    }
+}
+
+impl Error for UnknownModeBits {}
+
+impl From<UnknownModeBits> for io::Error {
+    fn from(err: UnknownModeBits) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// A transfer of a message failed validation before submission
+///
+/// Only problems the crate can detect by itself are attributed to a
+/// transfer.  When the kernel rejects a message, the whole message
+/// fails and there is no way to tell which transfer was the cause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchError {
+    /// Position of the offending transfer in the message
+    pub index: usize,
+    /// What is wrong with it
+    pub reason: TransferError,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "transfer {} of the message: {}", self.index, self.reason)
+    }
+}
+
+impl Error for BatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.reason)
+    }
+}
+
+impl From<BatchError> for io::Error {
+    fn from(err: BatchError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Mode flags other than the clock polarity and phase are set
+///
+/// Returned when converting `SpiModeFlags` into a `SpiMode`, which only
+/// covers `SPI_CPOL` and `SPI_CPHA`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtraModeFlags {
+    /// The bits that have no `SpiMode` equivalent
+    pub bits: u32,
+}
+
+impl fmt::Display for ExtraModeFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mode bits {:#x} have no SpiMode equivalent", self.bits)
+    }
+}
+
+impl Error for ExtraModeFlags {}
+
+impl From<ExtraModeFlags> for io::Error {
+    fn from(err: ExtraModeFlags) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// A mode flag name that does not name any `SpiModeFlags`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownModeName {
+    /// The name as it was given
+    pub name: String,
+}
+
+impl fmt::Display for UnknownModeName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown SPI mode flag {:?}", self.name)
+    }
+}
+
+impl Error for UnknownModeName {}
+
+impl From<UnknownModeName> for io::Error {
+    fn from(err: UnknownModeName) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Problem found by `SpidevOptions::validate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionError {
+    /// More than 32 bits per word were requested
+    BitsPerWordTooLarge { bits_per_word: u8 },
+    /// A maximum speed of zero Hz was requested
+    ZeroSpeed,
+    /// The mode contains bits this crate does not know about
+    UnknownModeBits { bits: u32 },
+    /// More than one of the Dual, Quad and Octal transmit (or receive)
+    /// flags are set
+    ConflictingBusWidth,
+    /// `SPI_3WIRE` is combined with a Dual, Quad or Octal bus width
+    ThreeWireMultiBit,
+    /// `lsb_first` disagrees with the `SPI_LSB_FIRST` bit of the mode,
+    /// which would silently override it
+    ConflictingLsbFirst,
+    /// A chip select setup or hold time does not fit the 16-bit
+    /// microsecond delay of a transfer
+    CsDelayTooLong { delay: Duration },
+}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionError::BitsPerWordTooLarge { bits_per_word } => write!(
+                f,
+                "{} bits per word exceeds the maximum of 32",
+                bits_per_word
+            ),
+            OptionError::ZeroSpeed => write!(f, "maximum speed must not be zero"),
+            OptionError::UnknownModeBits { bits } => write!(f, "unknown SPI mode bits {:#x}", bits),
+            OptionError::ConflictingBusWidth => {
+                write!(f, "several wide bus widths are selected at the same time")
+            }
+            OptionError::ThreeWireMultiBit => {
+                write!(
+                    f,
+                    "3-wire mode cannot be combined with Dual, Quad or Octal transfers"
+                )
+            }
+            OptionError::ConflictingLsbFirst => {
+                write!(f, "lsb_first disagrees with the SPI_LSB_FIRST mode bit")
+            }
+            OptionError::CsDelayTooLong { delay } => write!(
+                f,
+                "chip select delay of {:?} exceeds the maximum of {} microseconds",
+                delay,
+                u16::MAX
+            ),
+        }
+    }
+}
+
+impl Error for OptionError {}
+
+impl From<OptionError> for io::Error {
+    fn from(err: OptionError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Error conditions of a spidev device itself
+///
+/// These are reported inside an [`io::Error`]; use
+/// [`SpidevError::from_io`] to recognize them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpidevError {
+    /// The device has been removed, e.g. by unplugging a USB to SPI
+    /// bridge.  The handle will not recover and should be reopened.
+    Disconnected,
+    /// The controller rejected the requested word size
+    UnsupportedBitsPerWord { requested: u8 },
+    /// A message of `len` bytes exceeds the `bufsiz` of the spidev
+    /// module
+    MessageTooLong { len: usize, bufsiz: usize },
+}
+
+impl SpidevError {
+    /// Classify an error returned by a `Spidev` method
+    ///
+    /// This recognizes both errors already converted by `Spidev` and
+    /// the raw OS errors behind them.
+    pub fn from_io(err: &io::Error) -> Option<SpidevError> {
+        if let Some(err) = err.get_ref().and_then(|inner| inner.downcast_ref()) {
+            return Some(*err);
+        }
+        match err.raw_os_error() {
+            // spidev reports ESHUTDOWN once its device is unbound,
+            // other drivers ENODEV or ENXIO
+            Some(libc::ENODEV) | Some(libc::ENXIO) | Some(libc::ESHUTDOWN) => {
+                Some(SpidevError::Disconnected)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SpidevError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpidevError::Disconnected => write!(f, "the SPI device has been disconnected"),
+            SpidevError::UnsupportedBitsPerWord { requested } => write!(
+                f,
+                "the SPI controller does not support {} bits per word",
+                requested
+            ),
+            SpidevError::MessageTooLong { len, bufsiz } => write!(
+                f,
+                "transfer of {} bytes exceeds spidev bufsiz of {}",
+                len, bufsiz
+            ),
+        }
+    }
+}
+
+impl Error for SpidevError {}
+
+impl From<SpidevError> for io::Error {
+    fn from(err: SpidevError) -> io::Error {
+        match err {
+            SpidevError::Disconnected => io::Error::new(io::ErrorKind::NotConnected, err),
+            SpidevError::UnsupportedBitsPerWord { .. } | SpidevError::MessageTooLong { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, err)
+            }
+        }
+    }
+}
+
+/// Replace OS errors that have a `SpidevError` equivalent
+pub(crate) fn classify(err: io::Error) -> io::Error {
+    if err.get_ref().is_some() {
+        return err;
+    }
+    match SpidevError::from_io(&err) {
+        Some(spidev_err) => spidev_err.into(),
+        None => err,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_classify_disconnected() {
+        let err = classify(io::Error::from_raw_os_error(libc::ENODEV));
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert_eq!(SpidevError::from_io(&err), Some(SpidevError::Disconnected));
+        assert_eq!(
+            SpidevError::from_io(&io::Error::from_raw_os_error(libc::ESHUTDOWN)),
+            Some(SpidevError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_unsupported_bits_per_word() {
+        let spidev_err = SpidevError::UnsupportedBitsPerWord { requested: 12 };
+        let err: io::Error = spidev_err.into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(SpidevError::from_io(&classify(err)), Some(spidev_err));
+    }
+
+    #[test]
+    fn test_classify_passes_other_errors() {
+        let err = classify(io::Error::from_raw_os_error(libc::EINVAL));
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(SpidevError::from_io(&err), None);
+    }
+}
@@ -0,0 +1,47 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Commands shared by most SPI NOR flash chips
+//!
+//! These cover the JEDEC commands nearly every serial flash
+//! understands and double as examples of `Spidev::write_read`.  This
+//! module requires the `flash` feature.
+//!
+//! ```no_run
+//! # use spidev::{flash, Spidev};
+//! # fn main() -> std::io::Result<()> {
+//! let spi = Spidev::open("/dev/spidev0.0")?;
+//! let [manufacturer, memory_type, capacity] = flash::read_jedec_id(&spi)?;
+//! println!("{:02x} {:02x} {:02x}", manufacturer, memory_type, capacity);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::Spidev;
+use std::io;
+
+/// JEDEC Read Identification command
+pub const READ_JEDEC_ID: u8 = 0x9f;
+/// Read Status Register command
+pub const READ_STATUS: u8 = 0x05;
+
+/// Read the manufacturer, memory type and capacity bytes
+pub fn read_jedec_id(spi: &Spidev) -> io::Result<[u8; 3]> {
+    let mut id = [0u8; 3];
+    spi.write_read(&[READ_JEDEC_ID], &mut id)?;
+    Ok(id)
+}
+
+/// Read the first status register
+///
+/// Bit 0 is the write-in-progress (busy) flag on virtually all chips.
+pub fn read_status(spi: &Spidev) -> io::Result<u8> {
+    let mut status = [0u8];
+    spi.write_read(&[READ_STATUS], &mut status)?;
+    Ok(status[0])
+}
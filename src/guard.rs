@@ -0,0 +1,163 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Spidev, SpidevOptions, SpidevTransfer};
+use std::io;
+use std::ops::Deref;
+
+/// Restores a previous configuration when dropped
+///
+/// Returned by [`Spidev::with_temporary_options`].  The guard
+/// dereferences to the [`Spidev`] so transfers can be issued through
+/// it while the temporary configuration is in effect.
+///
+/// Errors cannot be reported from `drop`, so restoration on drop is
+/// best effort.  Call [`ConfigGuard::restore`] to restore explicitly
+/// and observe any error.
+#[derive(Debug)]
+pub struct ConfigGuard<'a> {
+    spidev: &'a Spidev,
+    saved: SpidevOptions,
+    restored: bool,
+}
+
+impl<'a> ConfigGuard<'a> {
+    pub(crate) fn new(spidev: &'a Spidev, saved: SpidevOptions) -> Self {
+        ConfigGuard {
+            spidev,
+            saved,
+            restored: false,
+        }
+    }
+
+    /// The configuration that will be restored
+    pub fn saved_options(&self) -> &SpidevOptions {
+        &self.saved
+    }
+
+    /// Restore the saved configuration, reporting any error
+    pub fn restore(mut self) -> io::Result<()> {
+        self.restored = true;
+        self.spidev.apply(&self.saved)
+    }
+}
+
+impl<'a> Deref for ConfigGuard<'a> {
+    type Target = Spidev;
+
+    fn deref(&self) -> &Spidev {
+        self.spidev
+    }
+}
+
+impl<'a> Drop for ConfigGuard<'a> {
+    fn drop(&mut self) {
+        if !self.restored {
+            let _ = self.spidev.apply(&self.saved);
+        }
+    }
+}
+
+/// Keeps a device in loopback mode until dropped
+///
+/// Returned by [`Spidev::enter_loopback`].  The mode the device had
+/// before is restored on drop, also when unwinding from a panic, which
+/// makes it safe to run a block of self-test transfers.  Like
+/// [`ConfigGuard`], the guard dereferences to the [`Spidev`].
+#[derive(Debug)]
+pub struct LoopbackGuard<'a> {
+    config: ConfigGuard<'a>,
+}
+
+impl<'a> LoopbackGuard<'a> {
+    pub(crate) fn new(config: ConfigGuard<'a>) -> Self {
+        LoopbackGuard { config }
+    }
+
+    /// Leave loopback mode, reporting any error
+    pub fn restore(self) -> io::Result<()> {
+        self.config.restore()
+    }
+}
+
+impl<'a> Deref for LoopbackGuard<'a> {
+    type Target = Spidev;
+
+    fn deref(&self) -> &Spidev {
+        &self.config
+    }
+}
+
+/// Keeps the chip selected across several messages until dropped
+///
+/// Returned by [`Spidev::select`].  The kernel deselects the chip at
+/// the end of every message unless the last transfer has `cs_change`
+/// set, which asks the controller to leave it selected.  All messages
+/// sent through the guard are adjusted accordingly: `cs_change` is
+/// cleared on every transfer but the last, and set on the last one.
+/// Dropping the guard sends an empty message which deselects the chip.
+///
+/// Leaving the chip selected is only a hint, which some controllers
+/// ignore, and messages for other devices on the same bus deselect it
+/// as well.  Unlike the other guards this one does not dereference to
+/// the [`Spidev`], so that transfers which would deselect the chip are
+/// not issued by accident.
+#[derive(Debug)]
+pub struct CsGuard<'a> {
+    spidev: &'a Spidev,
+    released: bool,
+}
+
+impl<'a> CsGuard<'a> {
+    pub(crate) fn new(spidev: &'a Spidev) -> Self {
+        CsGuard {
+            spidev,
+            released: false,
+        }
+    }
+
+    /// Perform a single transfer, keeping the chip selected
+    pub fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        self.transfer_multiple(std::slice::from_mut(transfer))
+    }
+
+    /// Perform several transfers in one message, keeping the chip selected
+    pub fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        if let Some((last, init)) = transfers.split_last_mut() {
+            for transfer in init {
+                transfer.cs_change = 0;
+            }
+            last.cs_change = 1;
+        }
+        self.spidev.transfer_multiple(transfers)
+    }
+
+    /// Write `buf`, keeping the chip selected
+    pub fn write(&self, buf: &[u8]) -> io::Result<()> {
+        self.transfer(&mut SpidevTransfer::write(buf))
+    }
+
+    /// Read into `buf`, keeping the chip selected
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<()> {
+        self.transfer(&mut SpidevTransfer::read(buf))
+    }
+
+    /// Deselect the chip, reporting any error
+    pub fn release(mut self) -> io::Result<()> {
+        self.released = true;
+        self.spidev.flush_rx()
+    }
+}
+
+impl<'a> Drop for CsGuard<'a> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.spidev.flush_rx();
+        }
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Round-trip times measured by `Spidev::measure_latency`
+///
+/// All values are in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    /// Fastest transfer
+    pub min_ns: u64,
+    /// Slowest transfer
+    pub max_ns: u64,
+    /// Average over all transfers
+    pub mean_ns: f64,
+    /// Population standard deviation
+    pub stddev_ns: f64,
+}
+
+impl LatencyStats {
+    /// Summarize a non-empty set of samples
+    pub(crate) fn from_samples(samples: &[u64]) -> LatencyStats {
+        let count = samples.len() as f64;
+        let mean = samples.iter().map(|&sample| sample as f64).sum::<f64>() / count;
+        let variance = samples
+            .iter()
+            .map(|&sample| (sample as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        LatencyStats {
+            min_ns: samples.iter().copied().min().unwrap_or(0),
+            max_ns: samples.iter().copied().max().unwrap_or(0),
+            mean_ns: mean,
+            stddev_ns: variance.sqrt(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_samples() {
+        let stats = LatencyStats::from_samples(&[2, 4, 4, 4, 5, 5, 7, 9]);
+        assert_eq!(stats.min_ns, 2);
+        assert_eq!(stats.max_ns, 9);
+        assert_eq!(stats.mean_ns, 5.0);
+        assert_eq!(stats.stddev_ns, 2.0);
+    }
+}
@@ -65,15 +65,74 @@
 //! }
 //! ```
 
+mod buffered;
+mod bus;
+#[cfg(feature = "bytes")]
+mod bytes_support;
+mod devfile;
+mod device;
+mod enumerate;
+mod error;
+#[cfg(feature = "flash")]
+pub mod flash;
+mod guard;
+mod latency;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod reusable;
+mod sequence;
+#[cfg(feature = "serde")]
+mod serde_support;
 pub mod spidevioctl;
-pub use crate::spidevioctl::SpidevTransfer;
+#[cfg(feature = "metrics")]
+mod stats;
+mod sysfs;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "tracing")]
+mod tracing_support;
+mod words;
+pub use crate::buffered::BufferedSpidev;
+pub use crate::bus::SpiBusManager;
+pub use crate::device::SpiDevice;
+pub use crate::enumerate::{SpiControllerInfo, SpidevInfo};
+pub use crate::error::{
+    BatchError, ExtraModeFlags, OptionError, SpidevError, TransferError, UnknownModeBits,
+    UnknownModeName,
+};
+pub use crate::guard::{ConfigGuard, CsGuard, LoopbackGuard};
+pub use crate::latency::LatencyStats;
+pub use crate::reusable::{ReusableTransfer, TransferHandle};
+pub use crate::sequence::TransferSequence;
+pub use crate::spidevioctl::{CsBehavior, SpidevTransfer};
+#[cfg(feature = "metrics")]
+pub use crate::stats::SpidevStats;
+pub use crate::sysfs::SpiCapabilities;
+pub use crate::words::ByteOrder;
 
+use crate::devfile::DevFile;
 use bitflags::bitflags;
+use libc::c_ulong;
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
+use std::iter;
+use std::mem;
+use std::os::unix::io::{AsFd, BorrowedFd, OwnedFd};
 use std::os::unix::prelude::*;
-use std::path::Path;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 // Constants extracted from linux/spi/spidev.h
 bitflags! {
@@ -112,13 +171,213 @@ bitflags! {
         const SPI_RX_DUAL = 0x400;
         /// Receive with 4 wires
         const SPI_RX_QUAD = 0x800;
+        /// Toggle chip select after every word (Linux 4.18 and later)
+        const SPI_CS_WORD = 0x1000;
+        /// Transmit with 8 wires (Linux 5.1 and later)
+        const SPI_TX_OCTAL = 0x2000;
+        /// Receive with 8 wires (Linux 5.1 and later)
+        const SPI_RX_OCTAL = 0x4000;
+    }
+}
+
+impl SpiModeFlags {
+    /// Convert a raw mode, failing if it contains unknown bits
+    ///
+    /// This is meant for modes coming from outside sources such as
+    /// configuration files.  Use `from_bits_retain` to keep unknown
+    /// bits instead.
+    pub fn try_from_raw(bits: u32) -> Result<SpiModeFlags, UnknownModeBits> {
+        SpiModeFlags::from_bits(bits).ok_or(UnknownModeBits {
+            bits: bits & !SpiModeFlags::all().bits(),
+        })
+    }
+
+    /// The mode as written with `SPI_IOC_WR_MODE`
+    ///
+    /// Returns `None` if any bit above the lowest 8 is set, in which
+    /// case the mode can only be written with `SPI_IOC_WR_MODE32`.
+    pub fn as_u8(self) -> Option<u8> {
+        u8::try_from(self.bits()).ok()
+    }
+
+    /// Look up a flag by a human readable name
+    ///
+    /// Names are those of the constants, matched case-insensitively and
+    /// with or without the `SPI_` prefix, so `"MODE_0"`, `"cpol"`,
+    /// `"CS_HIGH"` and `"SPI_TX_QUAD"` are all accepted.  Unlike
+    /// `from_name`, this is meant for configuration files and command
+    /// lines.
+    pub fn parse_name(name: &str) -> Option<SpiModeFlags> {
+        let name = name.trim().to_ascii_uppercase();
+        if name.starts_with("SPI_") {
+            SpiModeFlags::from_name(&name)
+        } else {
+            SpiModeFlags::from_name(&format!("SPI_{}", name))
+        }
+    }
+}
+
+/// Marks a message as in flight on a `Spidev` until dropped
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Release);
     }
 }
 
+/// One of the four standard SPI modes
+///
+/// This is the clock polarity and phase as numbered by datasheets,
+/// without the other flags of [`SpiModeFlags`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpiMode {
+    /// Clock idles low, data sampled on the rising edge
+    Mode0,
+    /// Clock idles low, data sampled on the falling edge
+    Mode1,
+    /// Clock idles high, data sampled on the falling edge
+    Mode2,
+    /// Clock idles high, data sampled on the rising edge
+    Mode3,
+}
+
+impl From<SpiMode> for SpiModeFlags {
+    fn from(mode: SpiMode) -> SpiModeFlags {
+        match mode {
+            SpiMode::Mode0 => SpiModeFlags::SPI_MODE_0,
+            SpiMode::Mode1 => SpiModeFlags::SPI_MODE_1,
+            SpiMode::Mode2 => SpiModeFlags::SPI_MODE_2,
+            SpiMode::Mode3 => SpiModeFlags::SPI_MODE_3,
+        }
+    }
+}
+
+impl TryFrom<SpiModeFlags> for SpiMode {
+    type Error = ExtraModeFlags;
+
+    /// Fails if any flag besides `SPI_CPOL` and `SPI_CPHA` is set
+    fn try_from(flags: SpiModeFlags) -> Result<SpiMode, ExtraModeFlags> {
+        let extra = flags - SpiModeFlags::SPI_MODE_3;
+        if !extra.is_empty() {
+            return Err(ExtraModeFlags { bits: extra.bits() });
+        }
+        Ok(match flags {
+            SpiModeFlags::SPI_MODE_0 => SpiMode::Mode0,
+            SpiModeFlags::SPI_MODE_1 => SpiMode::Mode1,
+            SpiModeFlags::SPI_MODE_2 => SpiMode::Mode2,
+            _ => SpiMode::Mode3,
+        })
+    }
+}
+
+/// First interval slept by `Spidev::poll_with_backoff`
+const POLL_BACKOFF_INITIAL: Duration = Duration::from_millis(1);
+/// Upper bound of the interval slept by `Spidev::poll_with_backoff`
+const POLL_BACKOFF_MAX: Duration = Duration::from_millis(100);
+
 /// Provide high-level access to Linux Spidev Driver
+///
+/// # Thread safety
+///
+/// `Spidev` is `Send` and `Sync`, and transfers only need `&self`.
+/// The spidev driver serializes access to a device internally, so each
+/// call that issues a single message (such as `transfer` or
+/// `transfer_multiple`) reaches the bus intact, even when several
+/// threads share one `Spidev`.  Nothing holds the bus *between*
+/// messages though: helpers that split their work into several
+/// messages (`write_frame` on large buffers, `poll_register`, ...) may
+/// be interleaved with other threads' messages, and a `configure` from
+/// one thread affects the transfers of all others.  Protocols that span
+/// several messages need their own locking, e.g. a `Mutex<Spidev>`.
+///
+/// The driver's lock cannot be tested without blocking; use
+/// `try_transfer` to skip a transfer while another message is in
+/// flight on the same handle.
 #[derive(Debug)]
 pub struct Spidev {
-    devfile: File,
+    devfile: DevFile,
+    access: Access,
+    path: Option<PathBuf>,
+    chunking: ChunkPolicy,
+    cs_change_default: AtomicBool,
+    /// Speed stamped onto helper transfers, zero for the device default
+    default_speed_hz: AtomicU32,
+    /// Chip select setup and hold times of helper messages, in µs
+    cs_setup_usecs: AtomicU16,
+    cs_hold_usecs: AtomicU16,
+    retry_on_eintr: bool,
+    skip_unchanged: bool,
+    in_flight: Arc<AtomicUsize>,
+    /// Last known word size of the device, zero if unknown
+    bits_per_word: AtomicU8,
+    #[cfg(feature = "metrics")]
+    stats: Arc<Mutex<SpidevStats>>,
+}
+
+/// Access mode a spidev device was opened with
+///
+/// The spidev driver does not check the access mode of its ioctls, so
+/// transfers and configuration work with any of them; only the `Read`
+/// and `Write` implementations are restricted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// `O_RDONLY`
+    ReadOnly,
+    /// `O_WRONLY`
+    WriteOnly,
+    /// `O_RDWR`
+    ReadWrite,
+}
+
+impl Access {
+    /// Whether `read()` is permitted
+    pub fn can_read(self) -> bool {
+        self != Access::WriteOnly
+    }
+
+    /// Whether `write()` is permitted
+    pub fn can_write(self) -> bool {
+        self != Access::ReadOnly
+    }
+
+    /// Determine the access mode of an open file
+    fn of(file: &File) -> Access {
+        // Fall back to assuming read/write access, in which case the
+        // kernel still rejects what is not permitted
+        match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) } & libc::O_ACCMODE {
+            libc::O_RDONLY => Access::ReadOnly,
+            libc::O_WRONLY => Access::WriteOnly,
+            _ => Access::ReadWrite,
+        }
+    }
+}
+
+/// How transfers larger than a message are split up
+///
+/// See `Spidev::set_chunking`.  A newly opened `Spidev` uses `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkPolicy {
+    /// Never split, leaving the kernel to reject messages which are
+    /// too large
+    None,
+    /// Split into pieces of the spidev module's `bufsiz`
+    Bufsiz,
+    /// Split into pieces of the given number of bytes
+    Fixed(usize),
+}
+
+/// One step of a message sent with `Spidev::exchange`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Send the bytes, discarding what is received
+    Write(Vec<u8>),
+    /// Receive the given number of bytes, sending zeroes
+    Read(usize),
+    /// Send the bytes and receive as many, full-duplex
+    Transfer(Vec<u8>),
+    /// Wait for the given number of microseconds
+    Delay(u16),
 }
 
 /// Options that control defaults for communication on a device
@@ -130,12 +389,28 @@ pub struct Spidev {
 /// Options that are not configured with one of the builder
 /// functions will not be modified in the kernel when
 /// `configure` is called.
-#[derive(Debug, Default, Clone, Copy, PartialEq)]
+///
+/// New options may be added in future releases, so the struct
+/// cannot be built with a struct expression outside this crate;
+/// start from `SpidevOptions::new()` and use the builder functions.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct SpidevOptions {
     pub bits_per_word: Option<u8>,
     pub max_speed_hz: Option<u32>,
     pub lsb_first: Option<bool>,
     pub spi_mode: Option<SpiModeFlags>,
+    /// Always write `spi_mode` with SPI_IOC_WR_MODE32
+    pub force_mode32: bool,
+    /// `cs_change` applied by the helper methods, see
+    /// `Spidev::set_cs_change_default`
+    pub cs_change: Option<bool>,
+    /// Deselect the device when the `Spidev` is dropped
+    pub reset_on_drop: Option<bool>,
+    /// Chip select setup and hold times applied by the helper methods
+    pub cs_setup_hold: Option<(Duration, Duration)>,
 }
 
 impl SpidevOptions {
@@ -160,6 +435,30 @@ impl SpidevOptions {
         self
     }
 
+    /// The maximum SPI transfer speed, in kHz
+    ///
+    /// # Panics
+    ///
+    /// Panics if the speed in Hz does not fit in a `u32`.
+    pub fn max_speed_khz(&mut self, khz: u32) -> &mut Self {
+        let hz = khz
+            .checked_mul(1_000)
+            .expect("max_speed_khz overflows u32 Hz");
+        self.max_speed_hz(hz)
+    }
+
+    /// The maximum SPI transfer speed, in MHz
+    ///
+    /// # Panics
+    ///
+    /// Panics if the speed in Hz does not fit in a `u32`.
+    pub fn max_speed_mhz(&mut self, mhz: u32) -> &mut Self {
+        let hz = mhz
+            .checked_mul(1_000_000)
+            .expect("max_speed_mhz overflows u32 Hz");
+        self.max_speed_hz(hz)
+    }
+
     /// The bit justification used to transfer SPI words
     ///
     /// Zero indicates MSB-first; other values indicate the less common
@@ -171,6 +470,47 @@ impl SpidevOptions {
         self
     }
 
+    /// The `cs_change` applied to messages built by the helper methods
+    ///
+    /// The kernel only knows `cs_change` as a per-transfer setting, so
+    /// this does not issue an ioctl: `configure` records it as the
+    /// device's default, as `Spidev::set_cs_change_default` does, and
+    /// helpers such as `write_read` stamp it onto their messages.
+    pub fn cs_change(&mut self, cs_change: bool) -> &mut Self {
+        self.cs_change = Some(cs_change);
+        self
+    }
+
+    /// Deselect the device when the `Spidev` is dropped
+    ///
+    /// Dropping the `Spidev` (including while unwinding from a panic)
+    /// then runs an empty message through the controller, which leaves
+    /// the chip deselected even if a previous message asked to keep it
+    /// selected.  Errors are ignored, as `Drop` cannot report them.
+    /// Like `cs_change`, this is handled by the crate rather than the
+    /// kernel; `Spidev::into_inner` does not reset the device.
+    pub fn reset_on_drop(&mut self, reset: bool) -> &mut Self {
+        self.reset_on_drop = Some(reset);
+        self
+    }
+
+    /// Chip select setup and hold times for messages built by helpers
+    ///
+    /// Helpers such as `write_read` then start each message with an
+    /// empty transfer waiting for `setup` after selecting the chip, and
+    /// wait at least `hold` after the last transfer before releasing
+    /// it.  Like `cs_change`, this is handled by the crate: transfers
+    /// passed to `transfer` and `transfer_multiple` are sent as they are.
+    ///
+    /// The kernel counts delays in whole microseconds in a 16-bit
+    /// field, so both times are rounded down to microseconds and must
+    /// not exceed 65.535ms.  Longer times are reported by `validate`
+    /// and rejected by `configure`.  Zero disables either delay.
+    pub fn cs_setup_hold(&mut self, setup: Duration, hold: Duration) -> &mut Self {
+        self.cs_setup_hold = Some((setup, hold));
+        self
+    }
+
     /// Set the SPI Transfer Mode
     ///
     /// Use the constants SPI_MODE_0..SPI_MODE_3; or if you prefer
@@ -182,22 +522,309 @@ impl SpidevOptions {
     /// rathern than the 32-bit one to target the greatest number of
     /// kernels.  SPI_IOC_WR_MODE32 is only present in 3.15+ kernels.
     /// SPI_IOC_WR_MODE32 will be used iff bits higher than those in
-    /// 8bits are provided (e.g. Dual/Quad Tx/Rx).
+    /// 8bits are provided (e.g. Dual/Quad/Octal Tx/Rx or SPI_CS_WORD).
     pub fn mode(&mut self, mode: SpiModeFlags) -> &mut Self {
+        self.spi_mode = Some(mode);
+        self.force_mode32 = false;
+        self
+    }
+
+    /// Set the SPI Transfer Mode using the 32-bit ioctl
+    ///
+    /// Unlike `mode`, this always uses SPI_IOC_WR_MODE32, whichever
+    /// bits are set.  This gives explicit control over the Dual/Quad
+    /// bits, which are only accessible through the 32-bit ioctl, but
+    /// requires a 3.15+ kernel.
+    pub fn mode32(&mut self, mode: SpiModeFlags) -> &mut Self {
+        self.spi_mode = Some(mode);
+        self.force_mode32 = true;
+        self
+    }
+
+    /// Set the SPI Transfer Mode by its number, 0 through 3
+    ///
+    /// This maps `n` to SPI_MODE_0..SPI_MODE_3, as datasheets usually
+    /// specify.  Flags other than SPI_CPOL and SPI_CPHA which were
+    /// already set (e.g. SPI_CS_HIGH) are preserved.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is greater than 3.
+    pub fn mode_number(&mut self, n: u8) -> &mut Self {
+        let mode = match n {
+            0 => SpiMode::Mode0,
+            1 => SpiMode::Mode1,
+            2 => SpiMode::Mode2,
+            3 => SpiMode::Mode3,
+            _ => panic!("invalid SPI mode number {}, expected 0..=3", n),
+        };
+        self.spi_mode(mode)
+    }
+
+    /// Set the SPI Transfer Mode from a comma separated list of names
+    ///
+    /// Each name is looked up with `SpiModeFlags::parse_name` and the
+    /// flags are combined, so `"MODE_3, CS_HIGH"` selects SPI mode 3
+    /// with an active high chip select.  Any previously staged mode is
+    /// replaced and, as with `mode`, the 8-bit ioctl is preferred
+    /// again after `mode32`.  Nothing is changed if one of the names
+    /// is unknown.
+    pub fn mode_names(&mut self, names: &str) -> Result<&mut Self, UnknownModeName> {
+        let mut mode = SpiModeFlags::empty();
+        for name in names.split(',').filter(|name| !name.trim().is_empty()) {
+            mode |= SpiModeFlags::parse_name(name).ok_or_else(|| UnknownModeName {
+                name: name.trim().to_owned(),
+            })?;
+        }
+        self.spi_mode = Some(mode);
+        self.force_mode32 = false;
+        Ok(self)
+    }
+
+    /// Set the SPI Transfer Mode from a [`SpiMode`]
+    ///
+    /// Like `mode_number`, flags other than SPI_CPOL and SPI_CPHA which
+    /// were already set are preserved.
+    pub fn spi_mode(&mut self, mode: SpiMode) -> &mut Self {
+        let others = self.spi_mode.unwrap_or_else(SpiModeFlags::empty) - SpiModeFlags::SPI_MODE_3;
+        self.spi_mode = Some(others | SpiModeFlags::from(mode));
+        self
+    }
+
+    /// Set or clear individual mode flags, keeping the others
+    ///
+    /// The staged mode starts out empty if none was set yet, so
+    /// `set_mode_flag(SpiModeFlags::SPI_CS_HIGH, true)` on fresh
+    /// options also selects SPI mode 0.
+    pub fn set_mode_flag(&mut self, flag: SpiModeFlags, enabled: bool) -> &mut Self {
+        let mut mode = self.spi_mode.unwrap_or_else(SpiModeFlags::empty);
+        mode.set(flag, enabled);
         self.spi_mode = Some(mode);
         self
     }
 
+    /// Write only the options that differ from the device's current state
+    ///
+    /// The current configuration is read first, which costs a few
+    /// ioctls but avoids the writes when switching back and forth
+    /// between the same settings on a shared bus.  `Spidev::configure`
+    /// writes every present option unless `set_skip_unchanged` is
+    /// enabled.
+    pub fn apply_minimal(&self, spi: &Spidev) -> io::Result<()> {
+        let current = spi.query_configuration()?;
+        spi.apply(&self.changes_from(&current))
+    }
+
+    /// The subset of these options which differs from `current`
+    fn changes_from(&self, current: &SpidevOptions) -> SpidevOptions {
+        // The kernel reports 0 bits per word for the default of 8
+        fn normalize_bpw(bpw: Option<u8>) -> Option<u8> {
+            bpw.map(|bpw| if bpw == 0 { 8 } else { bpw })
+        }
+
+        SpidevOptions {
+            bits_per_word: self
+                .bits_per_word
+                .filter(|&bpw| normalize_bpw(Some(bpw)) != normalize_bpw(current.bits_per_word)),
+            max_speed_hz: self
+                .max_speed_hz
+                .filter(|&speed| Some(speed) != current.max_speed_hz),
+            lsb_first: self
+                .lsb_first
+                .filter(|&lsb_first| Some(lsb_first) != current.lsb_first),
+            spi_mode: self.spi_mode.filter(|&mode| Some(mode) != current.spi_mode),
+            force_mode32: self.force_mode32,
+            cs_change: self
+                .cs_change
+                .filter(|&cs_change| Some(cs_change) != current.cs_change),
+            reset_on_drop: self
+                .reset_on_drop
+                .filter(|&reset| Some(reset) != current.reset_on_drop),
+            cs_setup_hold: self
+                .cs_setup_hold
+                .filter(|&times| Some(times) != current.cs_setup_hold),
+        }
+    }
+
+    /// The ioctls `Spidev::configure` would issue for these options
+    ///
+    /// Nothing is written to any device; this describes the writes in
+    /// the order `configure` performs them, for logging or testing.
+    pub fn planned_ioctls(&self) -> Vec<PlannedIoctl> {
+        let mut planned = Vec::new();
+        if let Some(bpw) = self.bits_per_word {
+            planned.push(PlannedIoctl::SetBitsPerWord(bpw));
+        }
+        if let Some(speed) = self.max_speed_hz {
+            planned.push(PlannedIoctl::SetMaxSpeedHz(speed));
+        }
+        if let Some(lsb_first) = self.lsb_first {
+            planned.push(PlannedIoctl::SetLsbFirst(lsb_first));
+        }
+        if let Some(spi_mode_flags) = self.spi_mode {
+            if self.force_mode32 {
+                planned.push(PlannedIoctl::SetMode32(spi_mode_flags));
+            } else {
+                planned.push(PlannedIoctl::SetMode(spi_mode_flags));
+            }
+        }
+        planned
+    }
+
+    /// Check the options for consistency without touching a device
+    ///
+    /// Every problem found is reported, not just the first one.  Passing
+    /// validation does not guarantee that a particular controller
+    /// supports the options, only that no controller would.
+    pub fn validate(&self) -> Result<(), Vec<OptionError>> {
+        let mut errors = Vec::new();
+        if let Some(bits_per_word) = self.bits_per_word {
+            if bits_per_word > 32 {
+                errors.push(OptionError::BitsPerWordTooLarge { bits_per_word });
+            }
+        }
+        if self.max_speed_hz == Some(0) {
+            errors.push(OptionError::ZeroSpeed);
+        }
+        if let Some((setup, hold)) = self.cs_setup_hold {
+            for delay in [setup, hold] {
+                if delay_usecs(delay).is_err() {
+                    errors.push(OptionError::CsDelayTooLong { delay });
+                }
+            }
+        }
+        if let Some(mode) = self.spi_mode {
+            if SpiModeFlags::from_bits(mode.bits()).is_none() {
+                errors.push(OptionError::UnknownModeBits { bits: mode.bits() });
+            }
+            let tx_wide =
+                SpiModeFlags::SPI_TX_DUAL | SpiModeFlags::SPI_TX_QUAD | SpiModeFlags::SPI_TX_OCTAL;
+            let rx_wide =
+                SpiModeFlags::SPI_RX_DUAL | SpiModeFlags::SPI_RX_QUAD | SpiModeFlags::SPI_RX_OCTAL;
+            if (mode & tx_wide).bits().count_ones() > 1 || (mode & rx_wide).bits().count_ones() > 1
+            {
+                errors.push(OptionError::ConflictingBusWidth);
+            }
+            if mode.contains(SpiModeFlags::SPI_3WIRE) && mode.intersects(tx_wide | rx_wide) {
+                errors.push(OptionError::ThreeWireMultiBit);
+            }
+            if let Some(lsb_first) = self.lsb_first {
+                if lsb_first != mode.contains(SpiModeFlags::SPI_LSB_FIRST) {
+                    errors.push(OptionError::ConflictingLsbFirst);
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The checks of `Spidev::can_configure`, given what is known
+    fn plausible(
+        &self,
+        caps: Option<&SpiCapabilities>,
+        mode32: bool,
+        kernel: Option<(u32, u32)>,
+    ) -> bool {
+        if self.validate().is_err() {
+            return false;
+        }
+        if let (Some(speed), Some(max)) = (self.max_speed_hz, caps.and_then(|c| c.max_speed_hz)) {
+            if speed > max {
+                return false;
+            }
+        }
+        if let Some(mode) = self.spi_mode {
+            if !mode32 && (self.force_mode32 || mode.as_u8().is_none()) {
+                return false;
+            }
+            let octal = SpiModeFlags::SPI_TX_OCTAL | SpiModeFlags::SPI_RX_OCTAL;
+            let too_new = |since| matches!(kernel, Some(version) if version < since);
+            if mode.contains(SpiModeFlags::SPI_CS_WORD) && too_new((4, 18))
+                || mode.intersects(octal) && too_new((5, 1))
+            {
+                return false;
+            }
+        }
+        true
+    }
+
     /// Finalize and build the SpidevOptions
     pub fn build(&self) -> Self {
         *self
     }
 }
 
+/// A configuration write that `Spidev::configure` would perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlannedIoctl {
+    /// `SPI_IOC_WR_BITS_PER_WORD`
+    SetBitsPerWord(u8),
+    /// `SPI_IOC_WR_MAX_SPEED_HZ`
+    SetMaxSpeedHz(u32),
+    /// `SPI_IOC_WR_LSB_FIRST`
+    SetLsbFirst(bool),
+    /// `SPI_IOC_WR_MODE`, or `SPI_IOC_WR_MODE32` when the flags do not
+    /// fit in 8 bits
+    SetMode(SpiModeFlags),
+    /// `SPI_IOC_WR_MODE32`
+    SetMode32(SpiModeFlags),
+}
+
+impl PlannedIoctl {
+    /// Name of the ioctl request, as in the kernel headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            PlannedIoctl::SetBitsPerWord(_) => "SPI_IOC_WR_BITS_PER_WORD",
+            PlannedIoctl::SetMaxSpeedHz(_) => "SPI_IOC_WR_MAX_SPEED_HZ",
+            PlannedIoctl::SetLsbFirst(_) => "SPI_IOC_WR_LSB_FIRST",
+            PlannedIoctl::SetMode(flags) if flags.as_u8().is_none() => "SPI_IOC_WR_MODE32",
+            PlannedIoctl::SetMode(_) => "SPI_IOC_WR_MODE",
+            PlannedIoctl::SetMode32(_) => "SPI_IOC_WR_MODE32",
+        }
+    }
+
+    /// Perform the write on `fd`
+    fn issue(self, fd: RawFd) -> io::Result<()> {
+        match self {
+            PlannedIoctl::SetBitsPerWord(bpw) => {
+                spidevioctl::set_bits_per_word(fd, bpw).map_err(|err| {
+                    if err.raw_os_error() == Some(libc::EINVAL) {
+                        SpidevError::UnsupportedBitsPerWord { requested: bpw }.into()
+                    } else {
+                        err
+                    }
+                })
+            }
+            PlannedIoctl::SetMaxSpeedHz(speed) => spidevioctl::set_max_speed_hz(fd, speed),
+            PlannedIoctl::SetLsbFirst(lsb_first) => spidevioctl::set_lsb_first(fd, lsb_first),
+            PlannedIoctl::SetMode(flags) => spidevioctl::set_mode(fd, flags),
+            PlannedIoctl::SetMode32(flags) => spidevioctl::set_mode32(fd, flags),
+        }
+    }
+}
+
 impl Spidev {
     /// Wrap an already opened [`File`] for use as an spidev
     pub fn new(devfile: File) -> Self {
-        Self { devfile }
+        Self {
+            access: Access::of(&devfile),
+            devfile: DevFile::new(devfile),
+            path: None,
+            chunking: ChunkPolicy::None,
+            cs_change_default: AtomicBool::new(false),
+            default_speed_hz: AtomicU32::new(0),
+            cs_setup_usecs: AtomicU16::new(0),
+            cs_hold_usecs: AtomicU16::new(0),
+            retry_on_eintr: true,
+            skip_unchanged: false,
+            in_flight: Arc::default(),
+            bits_per_word: AtomicU8::new(0),
+            #[cfg(feature = "metrics")]
+            stats: Arc::default(),
+        }
     }
 
     /// Open the spidev device with the provided path
@@ -206,12 +833,73 @@ impl Spidev {
     /// where the first number if the bus and the second number
     /// is the chip select on that bus for the device being targeted.
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Spidev> {
+        Self::open_with_access(path, Access::ReadWrite)
+    }
+
+    /// Open the spidev device with the provided path and access mode
+    ///
+    /// This allows opening nodes the user may only read or only write,
+    /// e.g. for a receive-only logging tool.  `Read` or `Write` calls
+    /// which the access mode does not permit fail with an error of
+    /// kind `PermissionDenied`.
+    ///
+    /// Like every `open` function of this crate, the device is opened
+    /// with `O_CLOEXEC`, so its file descriptor is not inherited by
+    /// programs started with `exec`.  See `set_cloexec` to change that.
+    pub fn open_with_access<P: AsRef<Path>>(path: P, access: Access) -> io::Result<Spidev> {
         let devfile = OpenOptions::new()
-            .read(true)
-            .write(true)
+            .read(access.can_read())
+            .write(access.can_write())
             .create(false)
-            .open(path)?;
-        Ok(Self::new(devfile))
+            .custom_flags(libc::O_CLOEXEC)
+            .open(path.as_ref())?;
+        Ok(Spidev {
+            path: Some(path.as_ref().to_owned()),
+            ..Self::new(devfile)
+        })
+    }
+
+    /// Open the device named by the `SPIDEV` environment variable
+    ///
+    /// When `SPIDEV` is unset (or empty), `/dev/spidev0.0` is opened.
+    /// This lets examples and integration tests run on boards with a
+    /// different bus numbering, e.g. `SPIDEV=/dev/spidev1.0 cargo run
+    /// --example spidev-bidir`.
+    pub fn open_default() -> io::Result<Spidev> {
+        Self::open(default_path(env::var_os(SPIDEV_ENV)))
+    }
+
+    /// Open the spidev device for a bus and chip select
+    ///
+    /// This opens the canonical `/dev/spidev{bus}.{chip_select}` node.
+    /// An error of kind `NotFound` naming the node is returned if it
+    /// does not exist.
+    pub fn open_bus(bus: u32, chip_select: u32) -> io::Result<Spidev> {
+        let path = format!("/dev/spidev{}.{}", bus, chip_select);
+        Self::open(&path).map_err(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                io::Error::new(err.kind(), format!("{} does not exist", path))
+            } else {
+                err
+            }
+        })
+    }
+
+    /// List the spidev devices present on the system
+    ///
+    /// `/dev` is scanned for `spidevB.C` nodes, and the result is
+    /// sorted by bus and then by chip select.
+    pub fn list() -> io::Result<Vec<SpidevInfo>> {
+        enumerate::list_in(Path::new("/dev"))
+    }
+
+    /// List the SPI controllers present on the system
+    ///
+    /// `/sys/class/spi_master` is scanned for controllers, each listed
+    /// with the spidev nodes found in `/dev` for its bus.  The result
+    /// is sorted by bus number.
+    pub fn controllers() -> io::Result<Vec<SpiControllerInfo>> {
+        enumerate::controllers_in(Path::new("/sys/class/spi_master"), Path::new("/dev"))
     }
 
     /// Get a reference to the underlying [`File`] object
@@ -221,124 +909,2492 @@ impl Spidev {
 
     /// Consume the object and get the underlying [`File`] object
     pub fn into_inner(self) -> File {
-        self.devfile
+        self.devfile.into_file()
     }
 
-    /// Write the provided configuration to this device
-    pub fn configure(&mut self, options: &SpidevOptions) -> io::Result<()> {
-        // write out each present option to the device.  Options
-        // that are None are left as-is, in order to reduce
-        // overhead
-        let fd = self.devfile.as_raw_fd();
-        if let Some(bpw) = options.bits_per_word {
-            spidevioctl::set_bits_per_word(fd, bpw)?;
-        }
-        if let Some(speed) = options.max_speed_hz {
-            spidevioctl::set_max_speed_hz(fd, speed)?;
-        }
-        if let Some(lsb_first) = options.lsb_first {
-            spidevioctl::set_lsb_first(fd, lsb_first)?;
-        }
-        if let Some(spi_mode_flags) = options.spi_mode {
-            spidevioctl::set_mode(fd, spi_mode_flags)?;
-        }
-        Ok(())
+    /// Wrap an already open spidev file descriptor
+    ///
+    /// Unlike `FromRawFd::from_raw_fd`, this is safe: the `OwnedFd`
+    /// guarantees the descriptor is open and owned by the caller.  The
+    /// descriptor is closed when the `Spidev` is dropped.
+    pub fn from_owned_fd(fd: OwnedFd) -> Spidev {
+        Spidev::new(File::from(fd))
     }
 
-    /// Read the current configuration from this device
-    pub fn query_configuration(&self) -> io::Result<SpidevOptions> {
-        let fd = self.devfile.as_raw_fd();
+    /// Consume the object and get the underlying file descriptor
+    pub fn into_owned_fd(self) -> OwnedFd {
+        OwnedFd::from(self.into_inner())
+    }
 
-        let bpw = spidevioctl::get_bits_per_word(fd)?;
-        let speed = spidevioctl::get_max_speed_hz(fd)?;
-        let lsb_first = (spidevioctl::get_lsb_first(fd)?) != 0;
+    /// Set the `cs_change` value applied by the helper methods
+    ///
+    /// Messages built by helpers such as `detect_bits_per_word` get
+    /// this value stamped onto their final transfer.  On the last
+    /// transfer of a message the kernel interprets `cs_change` as a
+    /// request to leave the chip selected once the message completes,
+    /// which allows polling a device over several messages without
+    /// releasing it.  Defaults to `false` (deselect after each message).
+    pub fn set_cs_change_default(&mut self, cs_change: bool) {
+        self.cs_change_default.store(cs_change, Ordering::Relaxed);
+    }
 
-        // Try to get the mode as 32-bit (`RD_MODE32`). Older kernels may return
-        // `ENOTTY` indicating 32-bit is not supported. In that case we retry in
-        // 8-bit mode.
-        let mode_bits = spidevioctl::get_mode_u32(fd).or_else(|err| {
-            if err.raw_os_error() == Some(libc::ENOTTY) {
-                spidevioctl::get_mode(fd).map(|value| value as u32)
-            } else {
-                Err(err)
-            }
-        })?;
+    /// Set the clock speed applied by the helper methods
+    ///
+    /// Transfers built by helpers such as `write_read` carry a
+    /// `speed_hz` of 0, which tells the kernel to use the device's
+    /// `max_speed_hz`.  With a default set, those transfers are sent at
+    /// `speed_hz` instead, while transfers given an explicit speed, e.g.
+    /// through `transfer_at_speed`, keep theirs.  Transfers passed to
+    /// `transfer` and `transfer_multiple` are sent as they are.
+    ///
+    /// Passing 0 clears the default, so that a `speed_hz` of 0 means
+    /// the device default again.  No default is set initially.
+    pub fn set_default_speed_hz(&mut self, speed_hz: u32) {
+        self.default_speed_hz.store(speed_hz, Ordering::Relaxed);
+    }
 
-        let mode = SpiModeFlags::from_bits_retain(mode_bits);
+    /// The speed set with `set_default_speed_hz`, 0 if none
+    pub fn default_speed_hz(&self) -> u32 {
+        self.default_speed_hz.load(Ordering::Relaxed)
+    }
 
-        let options = SpidevOptions::new()
-            .bits_per_word(bpw)
-            .max_speed_hz(speed)
-            .lsb_first(lsb_first)
-            .mode(mode)
-            .build();
+    /// Control whether `configure` skips options already in effect
+    ///
+    /// The kernel has no ioctl setting several options at once, so
+    /// `configure` issues one write per option, each of which makes the
+    /// controller driver set up the device again.  When enabled, the
+    /// current configuration is read first, as `apply_minimal` does,
+    /// and only the options that differ are written.  Reading takes
+    /// about as many system calls as it saves, but reads are cheap
+    /// compared to the setup behind each write.  Disabled by default.
+    pub fn set_skip_unchanged(&mut self, skip: bool) {
+        self.skip_unchanged = skip;
+    }
 
-        Ok(options)
+    /// Control whether ioctls interrupted by a signal are retried
+    ///
+    /// By default an ioctl failing with `EINTR` is transparently
+    /// reissued.  Disable this to observe such interruptions as errors
+    /// of kind `Interrupted`.
+    pub fn set_retry_on_eintr(&mut self, retry: bool) {
+        self.retry_on_eintr = retry;
     }
 
-    /// Perform a single transfer
-    pub fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
-        spidevioctl::transfer(self.devfile.as_raw_fd(), transfer)
+    /// The access mode this device was opened with
+    pub fn access(&self) -> Access {
+        self.access
     }
 
-    /// Perform multiple transfers in a single system call to the kernel
+    /// Set whether the file descriptor is closed on `exec`
     ///
-    /// Chaining together multiple requests like this can reduce latency
-    /// and be used for conveniently and efficient implementing some
-    /// protocols without extra round trips back to userspace.
-    pub fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
-        spidevioctl::transfer_multiple(self.devfile.as_raw_fd(), transfers)
+    /// Devices opened by this crate start out with close-on-exec set.
+    /// Clear it to hand the device to a helper program, or set it on a
+    /// descriptor of unknown origin passed to `new`.
+    pub fn set_cloexec(&self, cloexec: bool) -> io::Result<()> {
+        let fd = self.devfile.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if cloexec {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
     }
-}
 
-impl Read for Spidev {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.devfile.read(buf)
+    /// Split large transfers automatically according to `policy`
+    ///
+    /// This affects `transfer`, `write_read` and the `Read` and `Write`
+    /// implementations, which by default (`ChunkPolicy::None`) fail on
+    /// more than `bufsiz` bytes.  Each piece is sent as a message of
+    /// its own:
+    ///
+    /// * `transfer` deselects the chip between pieces, as
+    ///   `write_frame` does.  The last piece keeps the transfer's own
+    ///   `cs_change`.
+    /// * `write_read` sets `cs_change` at the end of each message but
+    ///   the last, asking the controller to keep the chip selected
+    ///   so the device sees one command.  Controllers may ignore this.
+    /// * `read` and `write` move at most one piece per call; `read_exact`
+    ///   and `write_all` loop over the pieces.
+    ///
+    /// A `Fixed` size should be a multiple of the word size.
+    pub fn set_chunking(&mut self, policy: ChunkPolicy) {
+        self.chunking = policy;
     }
-}
 
-impl Write for Spidev {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.devfile.write(buf)
+    /// The policy set with `set_chunking`
+    pub fn chunking(&self) -> ChunkPolicy {
+        self.chunking
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.devfile.flush()
+    /// Largest piece allowed by the chunking policy, if any
+    fn chunk_size(&self) -> Option<usize> {
+        match self.chunking {
+            ChunkPolicy::None => None,
+            ChunkPolicy::Bufsiz => Some(self.bufsiz()),
+            ChunkPolicy::Fixed(size) => Some(size.max(1)),
+        }
     }
-}
 
-impl AsRawFd for Spidev {
-    fn as_raw_fd(&self) -> RawFd {
-        self.devfile.as_raw_fd()
+    /// The path this device was opened with
+    ///
+    /// This is `None` for a `Spidev` created from an open [`File`]
+    /// with `new`.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::{SpiModeFlags, SpidevOptions};
+    /// Create a second handle to the device sharing its settings
+    fn try_clone(&self) -> io::Result<Spidev> {
+        Ok(Spidev {
+            devfile: self.devfile.try_clone()?,
+            access: self.access,
+            path: self.path.clone(),
+            chunking: self.chunking,
+            cs_change_default: AtomicBool::new(self.cs_change_default.load(Ordering::Relaxed)),
+            default_speed_hz: AtomicU32::new(self.default_speed_hz.load(Ordering::Relaxed)),
+            cs_setup_usecs: AtomicU16::new(self.cs_setup_usecs.load(Ordering::Relaxed)),
+            cs_hold_usecs: AtomicU16::new(self.cs_hold_usecs.load(Ordering::Relaxed)),
+            retry_on_eintr: self.retry_on_eintr,
+            skip_unchanged: self.skip_unchanged,
+            in_flight: Arc::clone(&self.in_flight),
+            bits_per_word: AtomicU8::new(self.bits_per_word.load(Ordering::Relaxed)),
+            #[cfg(feature = "metrics")]
+            stats: Arc::clone(&self.stats),
+        })
+    }
 
-    #[test]
-    fn test_spidev_options_all() {
-        let options = SpidevOptions::new()
-            .bits_per_word(8)
-            .max_speed_hz(20_000)
-            .lsb_first(false)
-            .mode(SpiModeFlags::SPI_MODE_0)
-            .build();
-        assert_eq!(options.bits_per_word, Some(8));
-        assert_eq!(options.max_speed_hz, Some(20_000));
-        assert_eq!(options.lsb_first, Some(false));
-        assert_eq!(options.spi_mode, Some(SpiModeFlags::SPI_MODE_0));
+    /// Traffic counters accumulated since opening or `reset_stats`
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> SpidevStats {
+        *self.lock_stats()
     }
 
-    #[test]
-    fn test_spidev_options_some() {
-        let mut options = SpidevOptions::new();
-        options.bits_per_word(10);
-        options.lsb_first(true);
-        assert_eq!(options.bits_per_word, Some(10));
-        assert_eq!(options.max_speed_hz, None);
-        assert_eq!(options.lsb_first, Some(true));
-        assert_eq!(options.spi_mode, None);
+    /// Reset all traffic counters to zero
+    #[cfg(feature = "metrics")]
+    pub fn reset_stats(&self) {
+        *self.lock_stats() = SpidevStats::default();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn lock_stats(&self) -> MutexGuard<'_, SpidevStats> {
+        // The counters stay consistent even if a holder panicked
+        self.stats.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Run an ioctl wrapper on the device, retrying on `EINTR` if enabled
+    fn ioctl<T, F: FnMut(RawFd) -> io::Result<T>>(&self, mut f: F) -> io::Result<T> {
+        let fd = self.devfile.as_raw_fd();
+        let result = if self.retry_on_eintr {
+            spidevioctl::retry_on_eintr(|| f(fd))
+        } else {
+            f(fd)
+        };
+        result.map_err(error::classify)
+    }
+
+    /// Check whether the device is still present
+    ///
+    /// This reads the mode of the device, which fails with
+    /// [`SpidevError::Disconnected`] once the device has been removed.
+    /// Any other outcome counts as connected.
+    pub fn is_connected(&self) -> bool {
+        match self.ioctl(spidevioctl::get_mode) {
+            Err(err) => SpidevError::from_io(&err) != Some(SpidevError::Disconnected),
+            Ok(_) => true,
+        }
+    }
+
+    /// Open the device again and apply `options` to it
+    ///
+    /// This recovers from the device disappearing and coming back, as
+    /// hot-pluggable bridges do: the path this `Spidev` was opened with
+    /// is opened again with the same access mode, `options` are written
+    /// with `configure`, and only then is the new file descriptor put
+    /// in place of the old one.  If any step fails the old descriptor
+    /// is kept and the error is returned, so the call can be retried.
+    ///
+    /// The `cs_change`, `cs_setup_hold` and `reset_on_drop` settings
+    /// carry over unless `options` changes them.  A `Spidev` created
+    /// from a [`File`] has no path to reopen and fails with an error of
+    /// kind `InvalidInput`.
+    pub fn reopen(&mut self, options: &SpidevOptions) -> io::Result<()> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "spidev was not opened from a path",
+            )
+        })?;
+        let mut fresh = Spidev::open_with_access(path, self.access)?;
+        fresh.cs_change_default.store(
+            self.cs_change_default.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        fresh.set_cs_setup_hold_usecs(self.cs_setup_hold_usecs());
+        fresh
+            .devfile
+            .set_reset_on_drop(self.devfile.reset_on_drop());
+        fresh.configure(options)?;
+
+        // The old descriptor is dead, so resetting it would only fail
+        self.devfile.set_reset_on_drop(false);
+        self.set_cs_setup_hold_usecs(fresh.cs_setup_hold_usecs());
+        self.devfile = fresh.devfile;
+        self.cs_change_default.store(
+            fresh.cs_change_default.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.bits_per_word.store(
+            fresh.bits_per_word.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        Ok(())
+    }
+
+    /// Write the provided configuration to this device
+    ///
+    /// Options are written one at a time, so if writing one of them
+    /// fails those written before it stay in effect and the device is
+    /// left partially configured.  Use `configure_checked` to avoid
+    /// this.
+    ///
+    /// A word size the controller does not support is reported as
+    /// [`SpidevError::UnsupportedBitsPerWord`].  See
+    /// `set_skip_unchanged` to only write options which differ from
+    /// the current configuration.
+    pub fn configure(&mut self, options: &SpidevOptions) -> io::Result<()> {
+        if self.skip_unchanged {
+            options.apply_minimal(self)
+        } else {
+            self.apply(options)
+        }
+    }
+
+    /// Write the provided configuration, rolling back on failure
+    ///
+    /// The options are checked with `SpidevOptions::validate` before
+    /// anything is written, failing with the first problem found.  If
+    /// writing one of them still fails, the fields present in `options`
+    /// are restored to the values read from the device beforehand (on
+    /// a best effort basis) and the original error is returned.
+    pub fn configure_checked(&mut self, options: &SpidevOptions) -> io::Result<()> {
+        if let Err(errors) = options.validate() {
+            return Err(errors[0].into());
+        }
+        let previous = self.query_configuration()?;
+        if let Err(err) = self.apply(options) {
+            let rollback = SpidevOptions {
+                bits_per_word: options.bits_per_word.and(previous.bits_per_word),
+                max_speed_hz: options.max_speed_hz.and(previous.max_speed_hz),
+                lsb_first: options.lsb_first.and(previous.lsb_first),
+                spi_mode: options.spi_mode.and(previous.spi_mode),
+                force_mode32: false,
+                cs_change: options.cs_change.and(previous.cs_change),
+                reset_on_drop: options.reset_on_drop.and(previous.reset_on_drop),
+                cs_setup_hold: options.cs_setup_hold.and(previous.cs_setup_hold),
+            };
+            let _ = self.apply(&rollback);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Check whether `configure` would plausibly accept `options`
+    ///
+    /// Nothing is written to the device.  The current configuration is
+    /// read, which fails if this is not a spidev device, and the options
+    /// are checked with `SpidevOptions::validate` and against what is
+    /// known about the device and the running kernel.  `false` is
+    /// returned for
+    ///
+    /// * a maximum speed above the `spi-max-frequency` of the device
+    ///   tree,
+    /// * mode flags beyond the first 8 bits on kernels without
+    ///   `SPI_IOC_WR_MODE32` (before Linux 3.15),
+    /// * `SPI_CS_WORD` before Linux 4.18 and the Octal flags before 5.1.
+    ///
+    /// Checks for which the information is not available pass.  `true`
+    /// is no guarantee, as controllers reject some settings, such as
+    /// unsupported word sizes, only when they are written.
+    pub fn can_configure(&self, options: &SpidevOptions) -> io::Result<bool> {
+        self.query_configuration()?;
+        let mode32 = match self.ioctl(spidevioctl::get_mode32) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOTTY) => false,
+            result => result.map(|_| true)?,
+        };
+        Ok(options.plausible(
+            self.capabilities().ok().as_ref(),
+            mode32,
+            sysfs::kernel_version(),
+        ))
+    }
+
+    /// Return the device to this crate's default configuration
+    ///
+    /// The kernel has no way to restore the configuration a device
+    /// started out with, so this writes a fixed set of sane defaults
+    /// instead: SPI mode 0 with all other mode flags cleared, most
+    /// significant bit first and 8 bits per word.  The maximum speed
+    /// is set to the `spi-max-frequency` of the device tree where
+    /// sysfs exposes it, and left unchanged otherwise.  Note that
+    /// these are not necessarily the device tree's settings, e.g. an
+    /// `spi-cs-high` property is not honored.
+    pub fn reset_defaults(&self) -> io::Result<()> {
+        let mut options = SpidevOptions::new();
+        options
+            .bits_per_word(8)
+            .lsb_first(false)
+            .mode(SpiModeFlags::SPI_MODE_0);
+        if let Some(speed) = self.capabilities().ok().and_then(|caps| caps.max_speed_hz) {
+            options.max_speed_hz(speed);
+        }
+        self.apply(&options)
+    }
+
+    /// Write the options present in `options` to the device
+    fn apply(&self, options: &SpidevOptions) -> io::Result<()> {
+        // Reject unrepresentable delays before writing anything
+        let cs_setup_hold = match options.cs_setup_hold {
+            Some((setup, hold)) => Some((delay_usecs(setup)?, delay_usecs(hold)?)),
+            None => None,
+        };
+        // write out each present option to the device.  Options
+        // that are None are left as-is, in order to reduce
+        // overhead
+        for planned in options.planned_ioctls() {
+            let result = self.ioctl(|fd| planned.issue(fd));
+            if let PlannedIoctl::SetBitsPerWord(bits) = planned {
+                self.record_bits_per_word(bits, result.is_ok());
+            }
+            result?;
+        }
+        if let Some(cs_change) = options.cs_change {
+            self.cs_change_default.store(cs_change, Ordering::Relaxed);
+        }
+        if let Some(usecs) = cs_setup_hold {
+            self.set_cs_setup_hold_usecs(usecs);
+        }
+        if let Some(reset) = options.reset_on_drop {
+            self.devfile.set_reset_on_drop(reset);
+        }
+        Ok(())
+    }
+
+    /// Read the current mode flags of this device
+    fn mode(&self) -> io::Result<SpiModeFlags> {
+        // Try to get the mode as 32-bit (`RD_MODE32`). Older kernels may return
+        // `ENOTTY` indicating 32-bit is not supported. In that case we retry in
+        // 8-bit mode.
+        let mode_bits = self.ioctl(spidevioctl::get_mode32).or_else(|err| {
+            if err.raw_os_error() == Some(libc::ENOTTY) {
+                self.ioctl(spidevioctl::get_mode).map(|value| value as u32)
+            } else {
+                Err(err)
+            }
+        })?;
+        Ok(SpiModeFlags::from_bits_retain(mode_bits))
+    }
+
+    /// Whether the `SPI_READY` handshake is enabled
+    pub fn ready_handshake(&self) -> io::Result<bool> {
+        Ok(self.mode()?.contains(SpiModeFlags::SPI_READY))
+    }
+
+    /// Enable or disable the `SPI_READY` handshake
+    ///
+    /// The current mode is read back and only the `SPI_READY` bit is
+    /// changed.  With the handshake enabled the device can hold off the
+    /// controller by pulling its ready line low, so a transfer only
+    /// progresses while the device is ready and may take arbitrarily
+    /// long; consider `transfer_timeout` for devices which might never
+    /// become ready.  Only a few controllers implement the handshake,
+    /// others reject the mode with `EINVAL`.
+    pub fn set_ready_handshake(&mut self, enable: bool) -> io::Result<()> {
+        self.modify_mode(|mode| mode.set(SpiModeFlags::SPI_READY, enable))
+    }
+
+    /// Change the mode flags of the device in place
+    ///
+    /// The current mode is read, passed to `f` for modification and
+    /// written back, e.g. `spi.modify_mode(|mode| mode.insert(SpiModeFlags::SPI_CS_HIGH))`.
+    /// Other threads changing the mode meanwhile can race with this.
+    pub fn modify_mode(&self, f: impl FnOnce(&mut SpiModeFlags)) -> io::Result<()> {
+        let mut mode = self.mode()?;
+        f(&mut mode);
+        self.ioctl(|fd| spidevioctl::set_mode(fd, mode))
+    }
+
+    /// Read the current configuration from this device
+    pub fn query_configuration(&self) -> io::Result<SpidevOptions> {
+        let bpw = self.ioctl(spidevioctl::get_bits_per_word)?;
+        let speed = self.ioctl(spidevioctl::get_max_speed_hz)?;
+        let lsb_first = (self.ioctl(spidevioctl::get_lsb_first)?) != 0;
+
+        let mode = self.mode()?;
+        let (setup, hold) = self.cs_setup_hold_usecs();
+
+        let options = SpidevOptions::new()
+            .bits_per_word(bpw)
+            .max_speed_hz(speed)
+            .lsb_first(lsb_first)
+            .mode(mode)
+            .cs_change(self.cs_change_default.load(Ordering::Relaxed))
+            .reset_on_drop(self.devfile.reset_on_drop())
+            .cs_setup_hold(
+                Duration::from_micros(setup.into()),
+                Duration::from_micros(hold.into()),
+            )
+            .build();
+
+        Ok(options)
+    }
+
+    /// Temporarily apply `options` until the returned guard is dropped
+    ///
+    /// The current configuration is read back before `options` are
+    /// written, and restored when the guard goes out of scope.  This
+    /// is useful to raise the clock for a bulk operation without
+    /// having to remember to lower it afterwards.
+    pub fn with_temporary_options(&self, options: &SpidevOptions) -> io::Result<ConfigGuard<'_>> {
+        let saved = self.query_configuration()?;
+        let guard = ConfigGuard::new(self, saved);
+        self.apply(options)?;
+        Ok(guard)
+    }
+
+    /// Put the device in loopback mode until the returned guard is dropped
+    ///
+    /// `SPI_LOOP` is added to the current mode, which is restored when
+    /// the guard goes out of scope.  In loopback mode the controller
+    /// receives what it sends (if it supports this at all), allowing
+    /// framing logic to be exercised without external hardware.
+    pub fn enter_loopback(&self) -> io::Result<LoopbackGuard<'_>> {
+        let mode = self.mode()?;
+        let saved = SpidevOptions::new().mode(mode).build();
+        let guard = ConfigGuard::new(self, saved);
+        self.ioctl(|fd| spidevioctl::set_mode(fd, mode | SpiModeFlags::SPI_LOOP))?;
+        Ok(LoopbackGuard::new(guard))
+    }
+
+    /// Select the chip until the returned guard is dropped
+    ///
+    /// An empty message asking to leave the chip selected is sent,
+    /// after which transfers issued through the guard keep it
+    /// selected.  See [`CsGuard`] for the limits of this.
+    pub fn select(&self) -> io::Result<CsGuard<'_>> {
+        let guard = CsGuard::new(self);
+        guard.transfer(&mut SpidevTransfer::default())?;
+        Ok(guard)
+    }
+
+    /// Read the capabilities of this device and its controller from sysfs
+    ///
+    /// This allows adapting to the hardware before calling
+    /// `configure`.  Fields for attributes not exposed by the kernel
+    /// are `None`; an error is only returned if the sysfs entry for
+    /// the device cannot be found at all.
+    pub fn capabilities(&self) -> io::Result<SpiCapabilities> {
+        let device_dir = sysfs::device_dir(&self.devfile)?;
+        Ok(sysfs::capabilities(&device_dir))
+    }
+
+    /// Read the clock rate actually achieved on the bus, in Hz
+    ///
+    /// Unlike the `max_speed_hz` setting, which is only a ceiling,
+    /// this is the rate resulting from the divisor chosen by the
+    /// controller.  Mainline kernels do not publish it, so `None` is
+    /// returned unless the controller driver exposes an
+    /// `actual_speed_hz` (or `speed_hz`) attribute in sysfs.
+    pub fn effective_speed_hz(&self) -> io::Result<Option<u32>> {
+        let device_dir = sysfs::device_dir(&self.devfile)?;
+        Ok(sysfs::effective_speed_hz(&device_dir))
+    }
+
+    /// Maximum number of bytes the kernel accepts in a single message
+    ///
+    /// This is the `bufsiz` parameter of the spidev module as found in
+    /// sysfs, or its default of 4096 bytes when it cannot be read.
+    pub fn bufsiz(&self) -> usize {
+        sysfs::bufsiz()
+    }
+
+    /// Perform a single transfer
+    ///
+    /// The length of the transfer must be a whole number of words,
+    /// e.g. even with 16 bits per word, or `MisalignedLength` is
+    /// returned without issuing the transfer.  This applies to all
+    /// transfer methods; those sending several transfers wrap it in a
+    /// [`BatchError`] naming the offending transfer.
+    pub fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        match self.chunk_size() {
+            Some(size) if transfer.len() > size => {
+                let mut pieces = transfer.chunks(size);
+                // Every piece but the last has `cs_change` cleared, so
+                // the chip is deselected after it
+                for piece in &mut pieces {
+                    self.transfer_counted(piece)?;
+                }
+                Ok(())
+            }
+            _ => self.transfer_counted(transfer).map(|_| ()),
+        }
+    }
+
+    /// Perform a single transfer, returning the number of bytes moved
+    ///
+    /// This is the count reported by the kernel, which should always
+    /// be the length of the transfer; anything less points at a
+    /// controller problem.
+    pub fn transfer_counted(&self, transfer: &mut SpidevTransfer) -> io::Result<usize> {
+        self.in_flight.fetch_add(1, Ordering::Acquire);
+        let _in_flight = InFlight(&self.in_flight);
+        self.transfer_unguarded(transfer)
+    }
+
+    /// Perform a single transfer unless another message is in flight
+    ///
+    /// The spidev driver takes a lock for every message and makes later
+    /// callers sleep until it is released; there is no way to ask the
+    /// kernel whether the device is busy.  Instead this handle (and the
+    /// worker threads of `transfer_timeout`) count the messages they
+    /// have in flight, and `Ok(false)` is returned without touching the
+    /// bus if there is one.  `Ok(false)` is also returned when the
+    /// controller driver rejects the transfer with `EBUSY`.
+    ///
+    /// Only messages sent through this `Spidev` are seen.  Other file
+    /// descriptors for the same device, and other devices on the same
+    /// controller, can still make the transfer wait for the bus.
+    pub fn try_transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<bool> {
+        if self
+            .in_flight
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Ok(false);
+        }
+        let _in_flight = InFlight(&self.in_flight);
+        match self.transfer_unguarded(transfer) {
+            Ok(_) => Ok(true),
+            Err(err) if err.raw_os_error() == Some(libc::EBUSY) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Perform a single transfer without counting it as in flight
+    fn transfer_unguarded(&self, transfer: &mut SpidevTransfer) -> io::Result<usize> {
+        self.validate_message(slice::from_ref(transfer))
+            .map_err(|err| io::Error::from(err.reason))?;
+        #[cfg(feature = "tracing")]
+        let span = tracing_support::message_span(self.as_raw_fd(), 1, transfer.len());
+        #[cfg(any(feature = "metrics", feature = "tracing"))]
+        let start = Instant::now();
+        let result = {
+            #[cfg(feature = "tracing")]
+            let _entered = span.enter();
+            self.ioctl(|fd| spidevioctl::transfer(fd, transfer))
+                .map_err(|err| self.explain_message_error(err, transfer.len()))
+        };
+        #[cfg(feature = "tracing")]
+        tracing_support::record(&span, &result, start.elapsed());
+        #[cfg(feature = "metrics")]
+        self.lock_stats().record(
+            transfer.tx_len(),
+            transfer.rx_len(),
+            start.elapsed(),
+            result.is_ok(),
+        );
+        result
+    }
+
+    /// Perform a single transfer, failing if fewer bytes were moved
+    ///
+    /// The count reported by the kernel is checked against the length
+    /// of the transfer, and an error of kind `UnexpectedEof` is
+    /// returned when it falls short.  `transfer` does not check this.
+    pub fn transfer_exact(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        let count = self.transfer_counted(transfer)?;
+        if count != transfer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("transfer moved {} of {} bytes", count, transfer.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Time full-duplex transfers to characterize the bus
+    ///
+    /// `iterations` transfers of `payload_len` zero bytes are issued
+    /// back to back, timing only the system call of each one, and
+    /// their round-trip times are summarized.  This includes the
+    /// system call and controller overhead as well as the time on the
+    /// wire, which helps weigh `max_speed_hz` against per-transfer
+    /// costs.  Fails with an error of kind `InvalidInput` if
+    /// `iterations` is zero.
+    pub fn measure_latency(
+        &self,
+        payload_len: usize,
+        iterations: usize,
+    ) -> io::Result<LatencyStats> {
+        if iterations == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "at least one iteration is needed",
+            ));
+        }
+        let tx = vec![0; payload_len];
+        let mut rx = vec![0; payload_len];
+        let mut transfers = [SpidevTransfer::read_write(&tx, &mut rx)];
+        self.finish_message(&mut transfers);
+
+        let mut samples = Vec::with_capacity(iterations);
+        for _ in 0..iterations {
+            let start = Instant::now();
+            self.submit_message(&mut transfers)?;
+            let elapsed = start.elapsed();
+            samples.push(u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX));
+        }
+        Ok(LatencyStats::from_samples(&samples))
+    }
+
+    /// Perform a single transfer and check it against a time budget
+    ///
+    /// The transfer always runs to completion; its duration is only
+    /// compared against `budget` afterwards, so this is a monitoring
+    /// aid rather than a means of preemption.  On success the time
+    /// actually taken is returned.  If the budget was exceeded an
+    /// error of kind `TimedOut` is returned instead, even though the
+    /// data has been transferred.
+    pub fn transfer_within_budget(
+        &self,
+        transfer: &mut SpidevTransfer,
+        budget: Duration,
+    ) -> io::Result<Duration> {
+        let start = Instant::now();
+        self.transfer(transfer)?;
+        let elapsed = start.elapsed();
+        if elapsed > budget {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "transfer took {:?}, exceeding its budget of {:?}",
+                    elapsed, budget
+                ),
+            ));
+        }
+        Ok(elapsed)
+    }
+
+    /// Perform a single transfer, giving up after `timeout`
+    ///
+    /// The ioctl runs on a worker thread using a duplicate of the file
+    /// descriptor and private copies of the buffers; the receive buffer
+    /// of `transfer` is only filled in if the transfer completes in
+    /// time.  Otherwise an error of kind `TimedOut` is returned.  A
+    /// blocked ioctl cannot be cancelled, so on timeout the worker
+    /// thread is left behind until the kernel completes or fails the
+    /// transfer, and later transfers on this device may queue behind
+    /// it.
+    pub fn transfer_timeout(
+        &self,
+        transfer: &mut SpidevTransfer,
+        timeout: Duration,
+    ) -> io::Result<()> {
+        let worker = self.try_clone()?;
+        let template = transfer.rebind(None, None);
+        let tx_buf = transfer.tx_slice().map(<[u8]>::to_vec);
+        let mut rx_buf = transfer.rx_slice_mut().map(|buf| vec![0; buf.len()]);
+
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let result = {
+                let mut owned = template.rebind(tx_buf.as_deref(), rx_buf.as_deref_mut());
+                worker.transfer(&mut owned)
+            };
+            // The receiver is gone if the caller already timed out
+            let _ = sender.send(result.map(|_| rx_buf));
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => {
+                if let (Some(received), Some(rx_slice)) = (result?, transfer.rx_slice_mut()) {
+                    rx_slice.copy_from_slice(&received);
+                }
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("transfer did not complete within {:?}", timeout),
+            )),
+            // The worker only drops the sender without sending by panicking
+            Err(RecvTimeoutError::Disconnected) => match handle.join() {
+                Err(payload) => panic::resume_unwind(payload),
+                Ok(()) => unreachable!("transfer worker exited without a result"),
+            },
+        }
+    }
+
+    /// Start a transfer in the background and return immediately
+    ///
+    /// The spidev ioctls are synchronous, so this is not asynchronous
+    /// I/O in the kernel's sense: the transfer runs on a worker thread
+    /// using a duplicate of the file descriptor.  The returned handle
+    /// owns `transfer`, keeping its buffers alive until it completes,
+    /// and gives it back along with the result from
+    /// `TransferHandle::wait`, also if it failed.  This lets a
+    /// single thread compute the next payload while the current one
+    /// is on the bus.  Starting several transfers at once is allowed,
+    /// but the kernel runs them one at a time in no particular order.
+    pub fn transfer_async(&self, transfer: ReusableTransfer) -> io::Result<TransferHandle> {
+        Ok(TransferHandle::spawn(self.try_clone()?, transfer))
+    }
+
+    /// Perform multiple transfers in a single system call to the kernel
+    ///
+    /// Chaining together multiple requests like this can reduce latency
+    /// and be used for conveniently and efficient implementing some
+    /// protocols without extra round trips back to userspace.
+    ///
+    /// The transfers are checked before the message is submitted, and
+    /// the first invalid one is reported as a [`BatchError`].  Errors
+    /// from the kernel apply to the message as a whole.
+    pub fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        self.transfer_multiple_counted(transfers).map(|_| ())
+    }
+
+    /// Perform multiple transfers after passing each one to `hook`
+    ///
+    /// `hook` is called with every transfer and its index, in order,
+    /// right before the message is submitted.  This is the place for
+    /// cross-cutting adjustments such as logging or a common delay,
+    /// e.g. `|transfer, _| transfer.delay_usecs = 5`.
+    pub fn transfer_multiple_with<F>(
+        &self,
+        transfers: &mut [SpidevTransfer],
+        mut hook: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&mut SpidevTransfer, usize),
+    {
+        for (index, transfer) in transfers.iter_mut().enumerate() {
+            hook(transfer, index);
+        }
+        self.transfer_multiple(transfers)
+    }
+
+    /// Perform multiple transfers using an explicit request code
+    ///
+    /// This is an escape hatch for experimenting with kernels whose
+    /// message ioctl differs from `SPI_IOC_MESSAGE`, without waiting for
+    /// a release of this crate.  The transfers are checked as with
+    /// `transfer_multiple`, but `op` is passed to the kernel as is;
+    /// [`spidevioctl::spi_ioc_message`] computes the standard code.
+    ///
+    /// # Safety
+    ///
+    /// See [`spidevioctl::transfer_multiple_with_op`].
+    pub unsafe fn transfer_multiple_with_op(
+        &self,
+        transfers: &mut [SpidevTransfer],
+        op: c_ulong,
+    ) -> io::Result<()> {
+        self.in_flight.fetch_add(1, Ordering::Acquire);
+        let _in_flight = InFlight(&self.in_flight);
+        self.validate_message(transfers)?;
+        self.ioctl(|fd| spidevioctl::transfer_multiple_with_op(fd, transfers, op))?;
+        Ok(())
+    }
+
+    /// Perform multiple transfers, returning the total number of bytes moved
+    ///
+    /// The count reported by the kernel is the sum of the lengths of
+    /// all transfers in the message.
+    pub fn transfer_multiple_counted(&self, transfers: &mut [SpidevTransfer]) -> io::Result<usize> {
+        self.in_flight.fetch_add(1, Ordering::Acquire);
+        let _in_flight = InFlight(&self.in_flight);
+        self.validate_message(transfers)?;
+        let len = transfers.iter().map(SpidevTransfer::len).sum();
+        #[cfg(feature = "tracing")]
+        let span = tracing_support::message_span(self.as_raw_fd(), transfers.len(), len);
+        #[cfg(any(feature = "metrics", feature = "tracing"))]
+        let start = Instant::now();
+        let result = {
+            #[cfg(feature = "tracing")]
+            let _entered = span.enter();
+            self.ioctl(|fd| spidevioctl::transfer_multiple(fd, transfers))
+                .map_err(|err| self.explain_message_error(err, len))
+        };
+        #[cfg(feature = "tracing")]
+        tracing_support::record(&span, &result, start.elapsed());
+        #[cfg(feature = "metrics")]
+        self.lock_stats().record(
+            transfers.iter().map(SpidevTransfer::tx_len).sum(),
+            transfers.iter().map(SpidevTransfer::rx_len).sum(),
+            start.elapsed(),
+            result.is_ok(),
+        );
+        result
+    }
+
+    /// Check the transfers of a message before submitting it
+    ///
+    /// Besides the bus widths, every transfer must hold a whole number
+    /// of words.  The word size is the transfer's own `bits_per_word`
+    /// if set, otherwise that of the device.  The latter is remembered
+    /// from `configure`, or read from the device the first time it
+    /// matters.
+    fn validate_message(&self, transfers: &[SpidevTransfer]) -> Result<(), BatchError> {
+        for (index, transfer) in transfers.iter().enumerate() {
+            let fail = |reason| BatchError { index, reason };
+            transfer.check().map_err(fail)?;
+            // Any whole number of 32-bit words fits every word size
+            if transfer.len() % 4 == 0 {
+                continue;
+            }
+            let bits_per_word = match transfer.bits_per_word {
+                0 => self.device_bits_per_word(),
+                bits => bits,
+            };
+            if transfer.len() % words::word_size(bits_per_word) != 0 {
+                return Err(fail(TransferError::MisalignedLength {
+                    len: transfer.len(),
+                    bits_per_word,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Word size of the device, assuming 8 bits if it cannot be read
+    fn device_bits_per_word(&self) -> u8 {
+        let cached = self.bits_per_word.load(Ordering::Relaxed);
+        if cached != 0 {
+            return cached;
+        }
+        match self.ioctl(spidevioctl::get_bits_per_word) {
+            Ok(bits) => {
+                // The kernel reports zero for its default of 8 bits
+                let bits = if bits == 0 { 8 } else { bits };
+                self.bits_per_word.store(bits, Ordering::Relaxed);
+                bits
+            }
+            Err(_) => 8,
+        }
+    }
+
+    /// Keep the cached word size in step with a write of `bits_per_word`
+    ///
+    /// After a failed write the size is forgotten, to be read again
+    /// when it is next needed.
+    fn record_bits_per_word(&self, bits_per_word: u8, written: bool) {
+        let cached = match (written, bits_per_word) {
+            (false, _) => 0,
+            (true, 0) => 8,
+            (true, bits) => bits,
+        };
+        self.bits_per_word.store(cached, Ordering::Relaxed);
+    }
+
+    /// Write the word size with the raw ioctl, updating the cache
+    fn write_bits_per_word(&self, bits_per_word: u8) -> io::Result<()> {
+        let result = self.ioctl(|fd| spidevioctl::set_bits_per_word(fd, bits_per_word));
+        self.record_bits_per_word(bits_per_word, result.is_ok());
+        result
+    }
+
+    /// Report `EMSGSIZE` for a message of `len` bytes with its cause
+    fn explain_message_error(&self, err: io::Error, len: usize) -> io::Error {
+        if err.raw_os_error() == Some(libc::EMSGSIZE) {
+            SpidevError::MessageTooLong {
+                len,
+                bufsiz: self.bufsiz(),
+            }
+            .into()
+        } else {
+            err
+        }
+    }
+
+    /// Maximum number of transfers the kernel accepts in one message
+    ///
+    /// This limit comes from the size field of the `SPI_IOC_MESSAGE`
+    /// request code (see [`spidevioctl::SPI_IOC_MESSAGE_MAX`]).  The
+    /// total length of a message is further limited to `bufsiz` bytes.
+    /// `transfer_multiple` rejects larger batches with an error of kind
+    /// `InvalidInput`; `transfer_multiple_chunked` splits them instead.
+    pub fn max_transfers_per_message() -> usize {
+        spidevioctl::SPI_IOC_MESSAGE_MAX
+    }
+
+    /// Perform any number of transfers, split into as few messages as possible
+    ///
+    /// Each message holds at most `max_transfers_per_message` transfers
+    /// and `bufsiz` bytes.  The chip is deselected between messages,
+    /// and the `cs_change` of the last transfer of each message takes
+    /// its end-of-message meaning, so only split batches whose
+    /// transfers do not depend on staying selected.  A single transfer
+    /// larger than `bufsiz` is still sent on its own and rejected by
+    /// the kernel.
+    pub fn transfer_multiple_chunked(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        let bufsiz = self.bufsiz();
+        let mut remaining = transfers;
+        while !remaining.is_empty() {
+            let len = message_len(remaining, Self::max_transfers_per_message(), bufsiz);
+            let (message, rest) = remaining.split_at_mut(len);
+            self.transfer_multiple(message)?;
+            remaining = rest;
+        }
+        Ok(())
+    }
+
+    /// Perform multiple transfers while keeping the chip selected
+    ///
+    /// `cs_change` is cleared on every transfer but the last, so the
+    /// chip select stays asserted for the whole sequence.  This is the
+    /// usual way to perform read-modify-write register accesses.  The
+    /// `cs_change` of the last transfer is left untouched.
+    pub fn transfer_keep_selected(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        if let Some((_, init)) = transfers.split_last_mut() {
+            for transfer in init {
+                transfer.cs_change = 0;
+            }
+        }
+        self.transfer_multiple(transfers)
+    }
+
+    /// Write several buffers as one continuous frame
+    ///
+    /// A single message is built with one write transfer per buffer
+    /// and `cs_change` cleared between them, so the device sees the
+    /// concatenation of `bufs` without the chip being deselected and
+    /// without copying the data.  Returns the total number of bytes
+    /// written.
+    pub fn write_vectored(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        let mut transfers: Vec<_> = bufs.iter().map(|buf| SpidevTransfer::write(buf)).collect();
+        self.finish_message(&mut transfers);
+        self.submit_message(&mut transfers)?;
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    /// Issue a vendor-specific ioctl which reads a `T` from the device
+    ///
+    /// This is an escape hatch for ioctls not modeled by this crate.
+    ///
+    /// # Safety
+    ///
+    /// See [`spidevioctl::ioctl_read`].
+    pub unsafe fn ioctl_read<T>(&self, op: c_ulong) -> io::Result<T> {
+        spidevioctl::ioctl_read(self.devfile.as_raw_fd(), op)
+    }
+
+    /// Issue a vendor-specific ioctl which writes a `T` to the device
+    ///
+    /// # Safety
+    ///
+    /// See [`spidevioctl::ioctl_write`].
+    pub unsafe fn ioctl_write<T>(&self, op: c_ulong, value: &T) -> io::Result<()> {
+        // The ioctl may change the word size behind our back
+        self.bits_per_word.store(0, Ordering::Relaxed);
+        spidevioctl::ioctl_write(self.devfile.as_raw_fd(), op, value)
+    }
+
+    /// Issue a vendor-specific ioctl which reads and updates a `T`
+    ///
+    /// # Safety
+    ///
+    /// See [`spidevioctl::ioctl_read_write`].
+    pub unsafe fn ioctl_read_write<T>(&self, op: c_ulong, value: &mut T) -> io::Result<()> {
+        self.bits_per_word.store(0, Ordering::Relaxed);
+        spidevioctl::ioctl_read_write(self.devfile.as_raw_fd(), op, value)
+    }
+
+    /// Write the entire buffer to the device using the transfer ioctl
+    ///
+    /// Unlike the `write()` system call behind `Write::write_all`,
+    /// each transfer is all-or-nothing, so the device never sees a
+    /// partial frame.  Buffers larger than `bufsiz` are split into
+    /// several messages of at most `bufsiz` bytes, each of which is
+    /// transferred completely.
+    pub fn write_frame(&self, buf: &[u8]) -> io::Result<()> {
+        for chunk in buf.chunks(self.bufsiz()) {
+            let mut transfers = [SpidevTransfer::write(chunk)];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+        }
+        Ok(())
+    }
+
+    /// Write `byte` to the device `count` times
+    ///
+    /// This is useful for clocking out 0xFF to an SD card or clearing
+    /// a display, without the caller having to allocate `count` bytes.
+    /// A single fill buffer of at most `bufsiz` bytes is reused, with
+    /// one message issued per `bufsiz` bytes.
+    pub fn write_repeated(&self, byte: u8, count: usize) -> io::Result<()> {
+        let fill = vec![byte; count.min(self.bufsiz())];
+        let mut remaining = count;
+        while remaining > 0 {
+            let chunk = remaining.min(fill.len());
+            let mut transfers = [SpidevTransfer::write(&fill[..chunk])];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+            remaining -= chunk;
+        }
+        Ok(())
+    }
+
+    /// Generate clock pulses for `count` bytes, ignoring the data
+    ///
+    /// 0xFF is shifted out with MOSI held high and nothing is received,
+    /// as needed for the dummy clocks of SD card and NAND init
+    /// sequences, which advance the device's state machine while it is
+    /// not listening to data.  This is `write_repeated(0xFF, count)`,
+    /// reusing one fill buffer of at most `bufsiz` bytes.
+    pub fn clock(&self, count: usize) -> io::Result<()> {
+        self.write_repeated(0xff, count)
+    }
+
+    /// Write a 16-bit value with the given byte order
+    ///
+    /// See [`ByteOrder`] for how this interacts with `lsb_first`.
+    pub fn write_u16(&self, value: u16, order: ByteOrder) -> io::Result<()> {
+        self.write_frame(&order.pack_u16(value))
+    }
+
+    /// Write 16-bit pixels, each most significant byte first
+    ///
+    /// This is the wire format of RGB565 displays, whatever the byte
+    /// order of the host.  The pixels are packed into a buffer of at
+    /// most `bufsiz` bytes, which is reused for each message, so whole
+    /// frames can be sent without the caller converting them.  The
+    /// chip is deselected between messages, as with `write_frame`.
+    pub fn write_pixels_be(&self, pixels: &[u16]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for chunk in pixels.chunks((self.bufsiz() / 2).max(1)) {
+            ByteOrder::BigEndian.pack_u16_slice(chunk, &mut buf);
+            let mut transfers = [SpidevTransfer::write(&buf)];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+        }
+        Ok(())
+    }
+
+    /// Write a 16-bit value, most significant byte first
+    pub fn write_u16_be(&self, value: u16) -> io::Result<()> {
+        self.write_u16(value, ByteOrder::BigEndian)
+    }
+
+    /// Write a 16-bit value, least significant byte first
+    pub fn write_u16_le(&self, value: u16) -> io::Result<()> {
+        self.write_u16(value, ByteOrder::LittleEndian)
+    }
+
+    /// Read a 16-bit value with the given byte order
+    ///
+    /// See [`ByteOrder`] for how this interacts with `lsb_first`.
+    pub fn read_u16(&self, order: ByteOrder) -> io::Result<u16> {
+        let mut rx_buf = [0u8; 2];
+        {
+            let mut transfers = [SpidevTransfer::read(&mut rx_buf)];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+        }
+        Ok(order.unpack_u16(rx_buf))
+    }
+
+    /// Read a 16-bit value, most significant byte first
+    pub fn read_u16_be(&self) -> io::Result<u16> {
+        self.read_u16(ByteOrder::BigEndian)
+    }
+
+    /// Read a 16-bit value, least significant byte first
+    pub fn read_u16_le(&self) -> io::Result<u16> {
+        self.read_u16(ByteOrder::LittleEndian)
+    }
+
+    /// Run an empty message through the controller
+    ///
+    /// The kernel has no way to flush a controller's FIFO, and spidev
+    /// does not buffer data itself, so there is nothing to discard.
+    /// What an empty message does achieve is to wait for any message
+    /// in progress on the device to complete, and to leave the chip
+    /// deselected afterwards.  No clock cycles are generated, but the
+    /// chip select may be pulsed, so this is only sent when called
+    /// explicitly; `Write::flush` does not use it.
+    pub fn flush_rx(&self) -> io::Result<()> {
+        let mut transfers = [SpidevTransfer::default()];
+        self.transfer_multiple(&mut transfers)
+    }
+
+    /// Write `tx` and then read into `rx` within a single message
+    ///
+    /// The chip stays selected between the write and the read, which
+    /// is how most devices expect a command to be followed by its
+    /// response.  The two buffers may differ in length.
+    pub fn write_read(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let size = match self.chunk_size() {
+            Some(size) if tx.len() + rx.len() > size => size,
+            _ => {
+                let mut transfers = [SpidevTransfer::write(tx), SpidevTransfer::read(rx)];
+                self.finish_message(&mut transfers);
+                return self.submit_message(&mut transfers);
+            }
+        };
+
+        let mut transfers: Vec<_> = tx
+            .chunks(size)
+            .map(SpidevTransfer::write)
+            .chain(rx.chunks_mut(size).map(SpidevTransfer::read))
+            .collect();
+        // Leave room for the transfer of the chip select setup time
+        let max_transfers = Self::max_transfers_per_message() - 1;
+        let lens = split_messages(&mut transfers, max_transfers, size);
+        let mut remaining = transfers.as_mut_slice();
+        for (index, &len) in lens.iter().enumerate() {
+            let (message, rest) = remaining.split_at_mut(len);
+            if index + 1 < lens.len() {
+                self.apply_default_speed(message);
+            } else {
+                self.finish_message(message);
+            }
+            self.submit_message(message)?;
+            remaining = rest;
+        }
+        Ok(())
+    }
+
+    /// Write a value and read it back to check that it took effect
+    ///
+    /// `write_cmd` is sent as one message.  Then `read_cmd` is sent
+    /// and `expected.len()` bytes are read back as with `write_read`,
+    /// and `true` is returned if they equal `expected`.  This is the
+    /// usual way to confirm writes to write-protected or one-time
+    /// programmable registers.  A mismatch is not an error: the caller
+    /// decides whether to retry or give up.
+    pub fn write_verify(
+        &self,
+        write_cmd: &[u8],
+        read_cmd: &[u8],
+        expected: &[u8],
+    ) -> io::Result<bool> {
+        let mut transfers = [SpidevTransfer::write(write_cmd)];
+        self.finish_message(&mut transfers);
+        self.submit_message(&mut transfers)?;
+
+        let mut response = vec![0; expected.len()];
+        self.write_read(read_cmd, &mut response)?;
+        Ok(response == expected)
+    }
+
+    /// Perform a full-duplex transfer at a specific clock speed
+    ///
+    /// The speed is set on the transfer itself, so the speed configured
+    /// for the device stays in effect for all other transfers.  This
+    /// allows e.g. reading a device ID slowly and streaming data fast
+    /// without reconfiguring in between.  `tx` and `rx` must have the
+    /// same length.
+    pub fn transfer_at_speed(&self, tx: &[u8], rx: &mut [u8], speed_hz: u32) -> io::Result<()> {
+        let transfer = SpidevTransfer::try_read_write(tx, rx)?.with_speed_hz(speed_hz);
+        let mut transfers = [transfer];
+        self.finish_message(&mut transfers);
+        self.submit_message(&mut transfers)
+    }
+
+    /// Send `tx` full-duplex and return the bytes received meanwhile
+    ///
+    /// A receive buffer of `tx.len()` bytes is allocated for the
+    /// caller, which is convenient for scripts and test harnesses.
+    pub fn transfer_to_vec(&self, tx: &[u8]) -> io::Result<Vec<u8>> {
+        let mut rx = vec![0; tx.len()];
+        {
+            let mut transfers = [SpidevTransfer::read_write(tx, &mut rx)];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+        }
+        Ok(rx)
+    }
+
+    /// Send `tx` full-duplex and return the bytes received meanwhile
+    ///
+    /// Both buffers live on the stack, so unlike `transfer_to_vec`
+    /// this does not allocate, which suits fixed-size frames in
+    /// real-time code.  The array is sent as a single transfer, so `N`
+    /// must not exceed `bufsiz`; larger arrays are rejected by the
+    /// kernel with [`SpidevError::MessageTooLong`].
+    pub fn transfer_array<const N: usize>(&self, tx: [u8; N]) -> io::Result<[u8; N]> {
+        let mut rx = [0; N];
+        {
+            let mut transfers = [SpidevTransfer::read_write(&tx, &mut rx)];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+        }
+        Ok(rx)
+    }
+
+    /// Send `ops` as a single message and return what was received
+    ///
+    /// The result holds one buffer per op, in order: the received bytes
+    /// for `Read` and `Transfer`, and an empty buffer for `Write` and
+    /// `Delay`.  Owning all buffers makes this easier to use from
+    /// scripts and tests than `transfer_multiple`.
+    pub fn exchange(&self, ops: &[Op]) -> io::Result<Vec<Vec<u8>>> {
+        let mut received: Vec<Vec<u8>> = ops
+            .iter()
+            .map(|op| match op {
+                Op::Read(len) => vec![0; *len],
+                Op::Transfer(tx) => vec![0; tx.len()],
+                Op::Write(_) | Op::Delay(_) => Vec::new(),
+            })
+            .collect();
+        {
+            let mut transfers: Vec<_> = ops
+                .iter()
+                .zip(received.iter_mut())
+                .map(|(op, rx)| match op {
+                    Op::Write(tx) => SpidevTransfer::write(tx),
+                    Op::Read(_) => SpidevTransfer::read(rx),
+                    Op::Transfer(tx) => SpidevTransfer::read_write(tx, rx),
+                    Op::Delay(microseconds) => SpidevTransfer::delay(*microseconds),
+                })
+                .collect();
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+        }
+        Ok(received)
+    }
+
+    /// Transfer words of any width from 1 to 32 bits, full-duplex
+    ///
+    /// Words wider than 8 bits occupy 2 (up to 16 bits) or 4 bytes (up
+    /// to 32 bits) in the kernel's buffers, right-justified and in
+    /// native byte order; this takes care of the packing.  `rx` receives
+    /// one word per word sent, with the undefined bits above
+    /// `bits_per_word` cleared.
+    ///
+    /// `bits_per_word` must match the word size the device is
+    /// configured for, as remembered from `configure` or read from the
+    /// device, so that the words are framed the way the device expects.
+    ///
+    /// An error of kind `InvalidInput` is returned if `bits_per_word`
+    /// is not within 1..=32 or differs from the configured word size,
+    /// if a word in `words` does not fit in `bits_per_word` bits, or if
+    /// `rx` and `words` differ in length.
+    pub fn transfer_words(
+        &self,
+        bits_per_word: u8,
+        words: &[u32],
+        rx: &mut [u32],
+    ) -> io::Result<()> {
+        if !(1..=32).contains(&bits_per_word) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bits per word is not within 1..=32", bits_per_word),
+            ));
+        }
+        let configured = self.device_bits_per_word();
+        if bits_per_word != configured {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bits per word requested, but the device is configured for {}",
+                    bits_per_word, configured
+                ),
+            ));
+        }
+        if words.len() != rx.len() {
+            return Err(TransferError::LengthMismatch {
+                tx_len: words.len(),
+                rx_len: rx.len(),
+            }
+            .into());
+        }
+        let max_word = u32::MAX >> (32 - u32::from(bits_per_word));
+        if let Some(word) = words.iter().find(|&&word| word > max_word) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("word {:#x} does not fit in {} bits", word, bits_per_word),
+            ));
+        }
+
+        let tx_buf = words::pack_words(bits_per_word, words);
+        let mut rx_buf = vec![0; tx_buf.len()];
+        {
+            let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+            transfer.bits_per_word = bits_per_word;
+            let mut transfers = [transfer];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+        }
+        words::unpack_words(bits_per_word, &rx_buf, rx);
+        Ok(())
+    }
+
+    /// Send a fixed size command and read a fixed size response
+    ///
+    /// This is `write_read` without any buffers to manage, e.g. reading
+    /// the JEDEC ID of a flash chip is
+    /// `let id: [u8; 3] = spi.command_response([0x9f])?;`.
+    pub fn command_response<const W: usize, const R: usize>(
+        &self,
+        cmd: [u8; W],
+    ) -> io::Result<[u8; R]> {
+        let mut response = [0; R];
+        self.write_read(&cmd, &mut response)?;
+        Ok(response)
+    }
+
+    /// Read one byte at a time until `sentinel` is received
+    ///
+    /// Each byte is read in a message of its own, shifting out a zero,
+    /// until `sentinel` arrives or `max_bytes` bytes have been read,
+    /// much like `BufRead::read_until`.  The sentinel counts towards
+    /// `max_bytes` and is only kept in the result if
+    /// `include_sentinel` is set.  The chip select follows the default
+    /// set with `set_cs_change_default` after each byte.
+    pub fn read_until(
+        &self,
+        sentinel: u8,
+        max_bytes: usize,
+        include_sentinel: bool,
+    ) -> io::Result<Vec<u8>> {
+        let mut received = Vec::new();
+        for _ in 0..max_bytes {
+            let mut byte = [0];
+            let mut transfers = [SpidevTransfer::read(&mut byte)];
+            self.finish_message(&mut transfers);
+            self.submit_message(&mut transfers)?;
+            if byte[0] != sentinel || include_sentinel {
+                received.push(byte[0]);
+            }
+            if byte[0] == sentinel {
+                break;
+            }
+        }
+        Ok(received)
+    }
+
+    /// Read consecutive registers starting at `first_addr`
+    ///
+    /// The address byte `first_addr | read_flag_mask` is sent, then
+    /// `out.len()` bytes are clocked in (zeroes are shifted out) within
+    /// the same message.  Many sensors flag reads with the top bit of
+    /// the address, i.e. a `read_flag_mask` of `0x80`, and some need an
+    /// extra bit to enable address auto-increment.  Whether and how
+    /// the address advances over multi-byte reads is device specific,
+    /// so check the datasheet before reading more than one register.
+    pub fn read_registers(
+        &self,
+        first_addr: u8,
+        read_flag_mask: u8,
+        out: &mut [u8],
+    ) -> io::Result<()> {
+        self.write_read(&[first_addr | read_flag_mask], out)
+    }
+
+    /// Apply the per-device defaults to a message built by a helper
+    fn finish_message(&self, transfers: &mut [SpidevTransfer]) {
+        self.apply_default_speed(transfers);
+        if let Some(last) = transfers.last_mut() {
+            last.cs_change = self.cs_change_default.load(Ordering::Relaxed) as u8;
+            // The delay of the last transfer precedes the deselect
+            let hold = self.cs_hold_usecs.load(Ordering::Relaxed);
+            last.delay_usecs = last.delay_usecs.max(hold);
+        }
+    }
+
+    /// Submit a message built by a helper, after the chip select setup time
+    fn submit_message(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        let setup = self.cs_setup_usecs.load(Ordering::Relaxed);
+        if setup == 0 {
+            return self.transfer_multiple(transfers);
+        }
+        // Check first so that errors name the caller's transfers
+        self.validate_message(transfers)?;
+        // The empty transfer waits with the chip already selected
+        let mut message: Vec<_> = iter::once(SpidevTransfer::delay(setup))
+            .chain(transfers.iter_mut().map(mem::take))
+            .collect();
+        let result = self.transfer_multiple(&mut message);
+        for (transfer, sent) in transfers.iter_mut().zip(message.drain(1..)) {
+            *transfer = sent;
+        }
+        result
+    }
+
+    fn cs_setup_hold_usecs(&self) -> (u16, u16) {
+        (
+            self.cs_setup_usecs.load(Ordering::Relaxed),
+            self.cs_hold_usecs.load(Ordering::Relaxed),
+        )
+    }
+
+    fn set_cs_setup_hold_usecs(&self, (setup, hold): (u16, u16)) {
+        self.cs_setup_usecs.store(setup, Ordering::Relaxed);
+        self.cs_hold_usecs.store(hold, Ordering::Relaxed);
+    }
+
+    /// Give transfers without an explicit speed the default speed
+    fn apply_default_speed(&self, transfers: &mut [SpidevTransfer]) {
+        let speed_hz = self.default_speed_hz();
+        for transfer in transfers.iter_mut().filter(|t| t.speed_hz == 0) {
+            transfer.speed_hz = speed_hz;
+        }
+    }
+
+    /// Poll a status until `predicate` accepts it, backing off between reads
+    ///
+    /// `read_cmd` is written and `status_len` bytes are read back in a
+    /// single message.  Whenever `predicate` rejects the status the
+    /// bus is left idle for an interval which starts at 1ms and
+    /// doubles after every attempt, up to 100ms.  This keeps the bus
+    /// free during slow operations such as flash erases.
+    ///
+    /// The last status read is returned once accepted.  If `max_wait`
+    /// elapses first an error of kind `TimedOut` is returned.
+    pub fn poll_with_backoff<F: FnMut(&[u8]) -> bool>(
+        &self,
+        read_cmd: &[u8],
+        status_len: usize,
+        max_wait: Duration,
+        mut predicate: F,
+    ) -> io::Result<Vec<u8>> {
+        let deadline = Instant::now() + max_wait;
+        let mut interval = POLL_BACKOFF_INITIAL;
+        let mut status = vec![0; status_len];
+        loop {
+            self.write_read(read_cmd, &mut status)?;
+            if predicate(&status) {
+                return Ok(status);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "device status was not accepted before the deadline",
+                ));
+            }
+            thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(POLL_BACKOFF_MAX);
+        }
+    }
+
+    /// Repeatedly read a register until `predicate` accepts its value
+    ///
+    /// `cmd` is written and `rx.len()` bytes are read back within one
+    /// message, sleeping `delay` between attempts.  Returns `true` as
+    /// soon as `predicate` accepts the value in `rx`, or `false` once
+    /// `max_attempts` reads were rejected.  A `max_attempts` of zero
+    /// is treated as a single attempt.
+    ///
+    /// This is the common "wait until the BUSY bit clears" loop; see
+    /// `poll_with_backoff` for a variant bounded by time instead.
+    pub fn poll_register<F: FnMut(&[u8]) -> bool>(
+        &self,
+        cmd: &[u8],
+        rx: &mut [u8],
+        mut predicate: F,
+        max_attempts: usize,
+        delay: Duration,
+    ) -> io::Result<bool> {
+        for attempt in 0..max_attempts.max(1) {
+            if attempt > 0 {
+                thread::sleep(delay);
+            }
+            self.write_read(cmd, rx)?;
+            if predicate(rx) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Empirically detect the word size a device responds to
+    ///
+    /// Each of the `candidates` is applied in turn, after which
+    /// `probe_cmd` is written and `expected.len()` bytes are read
+    /// back within the same message.  The first candidate for which
+    /// the response matches `expected` is returned, or `None` if no
+    /// candidate matched.  Candidates rejected by the controller are
+    /// skipped.
+    ///
+    /// The bits per word setting in effect before the call is always
+    /// restored, so the detected value has to be applied with
+    /// `configure` afterwards.
+    pub fn detect_bits_per_word(
+        &mut self,
+        probe_cmd: &[u8],
+        expected: &[u8],
+        candidates: &[u8],
+    ) -> io::Result<Option<u8>> {
+        // The word size changes below, so whatever was cached is stale
+        self.bits_per_word.store(0, Ordering::Relaxed);
+        let original = self.ioctl(spidevioctl::get_bits_per_word)?;
+        let detected = self.probe_bits_per_word(probe_cmd, expected, candidates);
+        let restored = self.write_bits_per_word(original);
+        let detected = detected?;
+        restored?;
+        Ok(detected)
+    }
+
+    fn probe_bits_per_word(
+        &self,
+        probe_cmd: &[u8],
+        expected: &[u8],
+        candidates: &[u8],
+    ) -> io::Result<Option<u8>> {
+        let mut response = vec![0; expected.len()];
+        for &bits_per_word in candidates {
+            // word sizes the controller does not support are rejected
+            // with EINVAL, those which do not fit the probe length with
+            // MisalignedLength
+            let result = self
+                .write_bits_per_word(bits_per_word)
+                .and_then(|_| self.write_read(probe_cmd, &mut response));
+            match result {
+                Ok(()) if response == expected => return Ok(Some(bits_per_word)),
+                Ok(()) => {}
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(err) if is_misaligned(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Environment variable naming the device opened by `open_default`
+const SPIDEV_ENV: &str = "SPIDEV";
+/// Device opened by `open_default` when `SPIDEV` is not set
+const DEFAULT_SPIDEV: &str = "/dev/spidev0.0";
+
+/// Path to open given the value of the `SPIDEV` environment variable
+fn default_path(var: Option<OsString>) -> PathBuf {
+    match var {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => PathBuf::from(DEFAULT_SPIDEV),
+    }
+}
+
+/// Whether `err` reports a transfer that is not a whole number of words
+fn is_misaligned(err: &io::Error) -> bool {
+    let reason = err.get_ref().and_then(|err| {
+        err.downcast_ref::<BatchError>()
+            .map(|err| &err.reason)
+            .or_else(|| err.downcast_ref::<TransferError>())
+    });
+    matches!(reason, Some(TransferError::MisalignedLength { .. }))
+}
+
+/// A delay in whole microseconds, as stored in a transfer
+fn delay_usecs(delay: Duration) -> Result<u16, TransferError> {
+    u16::try_from(delay.as_micros()).map_err(|_| TransferError::DelayTooLong { delay })
+}
+
+/// Number of leading transfers which fit in a single message
+///
+/// At least one transfer is taken so that progress is always made.
+fn message_len(transfers: &[SpidevTransfer], max_transfers: usize, bufsiz: usize) -> usize {
+    let mut bytes = 0;
+    for (count, transfer) in transfers.iter().enumerate() {
+        bytes += transfer.len();
+        if count > 0 && (count == max_transfers || bytes > bufsiz) {
+            return count;
+        }
+    }
+    transfers.len()
+}
+
+/// Group the pieces of a chunked `write_read` into messages
+///
+/// Returns the number of transfers in each message.  The last transfer
+/// of every message but the final one gets `cs_change` set, asking the
+/// controller to keep the chip selected in between.
+fn split_messages(
+    transfers: &mut [SpidevTransfer],
+    max_transfers: usize,
+    bufsiz: usize,
+) -> Vec<usize> {
+    let mut lens = Vec::new();
+    let mut remaining = transfers;
+    while !remaining.is_empty() {
+        let len = message_len(remaining, max_transfers, bufsiz);
+        let (message, rest) = remaining.split_at_mut(len);
+        if !rest.is_empty() {
+            message[len - 1].cs_change = 1;
+        }
+        lens.push(len);
+        remaining = rest;
+    }
+    lens
+}
+
+impl Read for Spidev {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.access.can_read() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "spidev was opened write-only",
+            ));
+        }
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let buf = match self.chunk_size() {
+            Some(size) if buf.len() > size => &mut buf[..size],
+            _ => buf,
+        };
+        let result = self
+            .devfile
+            .read(buf)
+            .map_err(|err| self.explain_message_error(error::classify(err), buf.len()));
+        #[cfg(feature = "metrics")]
+        self.lock_stats().record(
+            0,
+            *result.as_ref().unwrap_or(&0),
+            start.elapsed(),
+            result.is_ok(),
+        );
+        result
+    }
+}
+
+impl Write for Spidev {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.access.can_write() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "spidev was opened read-only",
+            ));
+        }
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let buf = match self.chunk_size() {
+            Some(size) if buf.len() > size => &buf[..size],
+            _ => buf,
+        };
+        let result = self
+            .devfile
+            .write(buf)
+            .map_err(|err| self.explain_message_error(error::classify(err), buf.len()));
+        #[cfg(feature = "metrics")]
+        self.lock_stats().record(
+            *result.as_ref().unwrap_or(&0),
+            0,
+            start.elapsed(),
+            result.is_ok(),
+        );
+        result
+    }
+
+    /// Writes are never buffered, so this does nothing.  It does not
+    /// touch the bus; see `Spidev::flush_rx` for that.
+    fn flush(&mut self) -> io::Result<()> {
+        self.devfile.flush()
+    }
+}
+
+impl From<File> for Spidev {
+    fn from(devfile: File) -> Spidev {
+        Spidev::new(devfile)
+    }
+}
+
+impl TryFrom<&Path> for Spidev {
+    type Error = io::Error;
+
+    fn try_from(path: &Path) -> io::Result<Spidev> {
+        Spidev::open(path)
+    }
+}
+
+impl TryFrom<&str> for Spidev {
+    type Error = io::Error;
+
+    fn try_from(path: &str) -> io::Result<Spidev> {
+        Spidev::open(path)
+    }
+}
+
+impl From<OwnedFd> for Spidev {
+    fn from(fd: OwnedFd) -> Spidev {
+        Spidev::from_owned_fd(fd)
+    }
+}
+
+impl From<Spidev> for OwnedFd {
+    fn from(spidev: Spidev) -> OwnedFd {
+        spidev.into_owned_fd()
+    }
+}
+
+impl AsRawFd for Spidev {
+    fn as_raw_fd(&self) -> RawFd {
+        self.devfile.as_raw_fd()
+    }
+}
+
+impl AsFd for Spidev {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner().as_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        split_messages, Access, BatchError, ChunkPolicy, OptionError, PlannedIoctl,
+        SpiCapabilities, SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer, TransferError,
+    };
+    use std::io::prelude::*;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    #[test]
+    fn test_transfer_timeout_propagates_errors() {
+        // /dev/null rejects the transfer ioctl straight away
+        let spi = Spidev::open("/dev/null").unwrap();
+        let tx_buf = [1, 2, 3];
+        let mut rx_buf = [0; 3];
+        let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+        let err = spi
+            .transfer_timeout(&mut transfer, Duration::from_secs(5))
+            .unwrap_err();
+        assert_ne!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_open_with_access() {
+        let mut spi = Spidev::open_with_access("/dev/null", Access::ReadOnly).unwrap();
+        assert_eq!(spi.access(), Access::ReadOnly);
+        let err = spi.write(&[0]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(spi.read(&mut [0; 4]).unwrap(), 0);
+
+        let spi = Spidev::open_with_access("/dev/null", Access::WriteOnly).unwrap();
+        assert_eq!(spi.access(), Access::WriteOnly);
+    }
+
+    #[test]
+    fn test_default_path() {
+        use std::path::Path;
+        assert_eq!(super::default_path(None), Path::new("/dev/spidev0.0"));
+        assert_eq!(
+            super::default_path(Some("".into())),
+            Path::new("/dev/spidev0.0")
+        );
+        assert_eq!(
+            super::default_path(Some("/dev/spidev1.2".into())),
+            Path::new("/dev/spidev1.2")
+        );
+    }
+
+    #[test]
+    fn test_message_len() {
+        let buf = [0u8; 100];
+        let transfers: Vec<_> = (0..5).map(|_| SpidevTransfer::write(&buf)).collect();
+        assert_eq!(super::message_len(&transfers, 511, 4096), 5);
+        assert_eq!(super::message_len(&transfers, 2, 4096), 2);
+        assert_eq!(super::message_len(&transfers, 511, 250), 2);
+        // an oversized transfer is still taken on its own
+        assert_eq!(super::message_len(&transfers, 511, 50), 1);
+        assert_eq!(super::message_len(&[], 511, 4096), 0);
+    }
+
+    #[test]
+    fn test_cloexec() {
+        use std::os::unix::io::AsRawFd;
+
+        let spi = Spidev::open("/dev/null").unwrap();
+        let cloexec = || unsafe { libc::fcntl(spi.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC;
+        assert_ne!(cloexec(), 0);
+        spi.set_cloexec(false).unwrap();
+        assert_eq!(cloexec(), 0);
+        spi.set_cloexec(true).unwrap();
+        assert_ne!(cloexec(), 0);
+    }
+
+    #[test]
+    fn test_transfer_multiple_with_op() {
+        use crate::spidevioctl::spi_ioc_message;
+
+        let spi = Spidev::open("/dev/null").unwrap();
+        let mut transfers = [SpidevTransfer::delay(0)];
+        let op = spi_ioc_message(transfers.len()).unwrap();
+        let err = unsafe { spi.transfer_multiple_with_op(&mut transfers, op) }.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+    }
+
+    #[test]
+    fn test_cs_setup_hold() {
+        // No ioctls are needed, so even /dev/null takes the options
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        let options = SpidevOptions::new()
+            .cs_setup_hold(Duration::from_micros(10), Duration::from_micros(20))
+            .build();
+        spi.configure(&options).unwrap();
+        assert_eq!(spi.cs_setup_hold_usecs(), (10, 20));
+
+        let mut transfers = [SpidevTransfer::delay(0), SpidevTransfer::delay(50)];
+        spi.finish_message(&mut transfers);
+        assert_eq!(transfers[0].delay_usecs, 0);
+        assert_eq!(transfers[1].delay_usecs, 50);
+        let mut transfers = [SpidevTransfer::delay(5)];
+        spi.finish_message(&mut transfers);
+        assert_eq!(transfers[0].delay_usecs, 20);
+
+        let options = SpidevOptions::new()
+            .cs_setup_hold(Duration::from_millis(70), Duration::ZERO)
+            .build();
+        assert!(spi.configure(&options).is_err());
+        assert_eq!(spi.cs_setup_hold_usecs(), (10, 20));
+    }
+
+    #[test]
+    fn test_default_speed_hz() {
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        let mut transfers = [
+            SpidevTransfer::delay(0),
+            SpidevTransfer::delay(0).with_speed_hz(1_000),
+        ];
+        spi.finish_message(&mut transfers);
+        assert_eq!(transfers[0].speed_hz, 0);
+
+        spi.set_default_speed_hz(500_000);
+        assert_eq!(spi.default_speed_hz(), 500_000);
+        spi.finish_message(&mut transfers);
+        assert_eq!(transfers[0].speed_hz, 500_000);
+        assert_eq!(transfers[1].speed_hz, 1_000);
+    }
+
+    #[test]
+    fn test_chunking_write() {
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        let buf = vec![0u8; spi.bufsiz() + 10];
+        assert_eq!(spi.chunking(), ChunkPolicy::None);
+        assert_eq!(spi.write(&buf).unwrap(), buf.len());
+
+        spi.set_chunking(ChunkPolicy::Bufsiz);
+        assert_eq!(spi.write(&buf).unwrap(), spi.bufsiz());
+
+        spi.set_chunking(ChunkPolicy::Fixed(4));
+        assert_eq!(spi.write(&buf[..10]).unwrap(), 4);
+        assert_eq!(spi.write(&buf[..3]).unwrap(), 3);
+        spi.write_all(&buf).unwrap();
+    }
+
+    #[test]
+    fn test_chunking_transfer() {
+        let tx_buf = [0u8; 10];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        transfer.cs_change = 1;
+        let pieces = transfer.chunks(4);
+        let lens: Vec<_> = pieces.iter().map(|piece| piece.len()).collect();
+        assert_eq!(lens, [4, 4, 2]);
+        let cs_change: Vec<_> = pieces.iter().map(|piece| piece.cs_change).collect();
+        assert_eq!(cs_change, [0, 0, 1]);
+
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        spi.set_chunking(ChunkPolicy::Fixed(4));
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        assert!(spi.transfer(&mut transfer).is_err());
+    }
+
+    #[test]
+    fn test_chunking_write_read() {
+        let tx = [0u8; 5];
+        let mut rx = [0u8; 3];
+        let mut transfers: Vec<_> = tx
+            .chunks(4)
+            .map(SpidevTransfer::write)
+            .chain(rx.chunks_mut(4).map(SpidevTransfer::read))
+            .collect();
+        let lens: Vec<_> = transfers.iter().map(|transfer| transfer.len()).collect();
+        assert_eq!(lens, [4, 1, 3]);
+
+        assert_eq!(split_messages(&mut transfers, 8, 4), [1, 2]);
+        let cs_change: Vec<_> = transfers
+            .iter()
+            .map(|transfer| transfer.cs_change)
+            .collect();
+        assert_eq!(cs_change, [1, 0, 0]);
+
+        // The transfer count limit splits messages too
+        for transfer in &mut transfers {
+            transfer.cs_change = 0;
+        }
+        assert_eq!(split_messages(&mut transfers, 1, 16), [1, 1, 1]);
+        let cs_change: Vec<_> = transfers
+            .iter()
+            .map(|transfer| transfer.cs_change)
+            .collect();
+        assert_eq!(cs_change, [1, 1, 0]);
+    }
+
+    #[test]
+    fn test_transfer_multiple_with() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        let mut transfers = [SpidevTransfer::delay(0), SpidevTransfer::delay(0)];
+        let mut seen = Vec::new();
+        let result = spi.transfer_multiple_with(&mut transfers, |transfer, index| {
+            transfer.delay_usecs = 5;
+            seen.push(index);
+        });
+        assert!(result.is_err());
+        assert_eq!(seen, [0, 1]);
+        assert!(transfers.iter().all(|transfer| transfer.delay_usecs == 5));
+    }
+
+    #[test]
+    fn test_skip_unchanged_reads_first() {
+        // /dev/null accepts no ioctls, so only the reads can fail here
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        assert!(spi.configure(&SpidevOptions::new()).is_ok());
+        spi.set_skip_unchanged(true);
+        assert!(spi.configure(&SpidevOptions::new()).is_err());
+    }
+
+    #[test]
+    fn test_conversions() {
+        use std::convert::TryFrom;
+        use std::path::Path;
+
+        let spi: Spidev = std::fs::File::open("/dev/null").unwrap().into();
+        assert_eq!(spi.path(), None);
+        let spi = Spidev::try_from(Path::new("/dev/null")).unwrap();
+        assert_eq!(spi.path(), Some(Path::new("/dev/null")));
+        assert!(Spidev::try_from("/dev/null").is_ok());
+        assert_eq!(
+            Spidev::try_from("/nonexistent/spidev").unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_owned_fd() {
+        use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
+
+        let fd = OwnedFd::from(std::fs::File::open("/dev/null").unwrap());
+        let raw = fd.as_raw_fd();
+        let spi = Spidev::from_owned_fd(fd);
+        assert_eq!(spi.as_fd().as_raw_fd(), raw);
+        assert_eq!(spi.path(), None);
+
+        let fd = spi.into_owned_fd();
+        assert_eq!(fd.as_raw_fd(), raw);
+        let spi: Spidev = fd.into();
+        assert_eq!(OwnedFd::from(spi).as_raw_fd(), raw);
+    }
+
+    #[test]
+    fn test_reopen() {
+        // /dev/null rejects the configuration, leaving the handle as is
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        assert_eq!(spi.path(), Some(std::path::Path::new("/dev/null")));
+        let options = SpidevOptions::new().max_speed_hz(1_000_000).build();
+        assert!(spi.reopen(&options).is_err());
+        assert!(spi.reopen(&SpidevOptions::new()).is_ok());
+
+        let mut spi = Spidev::new(std::fs::File::open("/dev/null").unwrap());
+        assert_eq!(spi.path(), None);
+        let err = spi.reopen(&SpidevOptions::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_word_alignment() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        let tx_buf = [0u8; 3];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        transfer.bits_per_word = 16;
+        let err = spi.transfer(&mut transfer).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<TransferError>()),
+            Some(&TransferError::MisalignedLength {
+                len: 3,
+                bits_per_word: 16
+            })
+        );
+
+        // The device word size is remembered once known
+        spi.bits_per_word.store(12, Ordering::Relaxed);
+        let mut transfers = [
+            SpidevTransfer::write(&tx_buf[..2]),
+            SpidevTransfer::write(&tx_buf),
+        ];
+        let err = spi.transfer_multiple(&mut transfers).unwrap_err();
+        assert_eq!(
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<BatchError>()),
+            Some(&BatchError {
+                index: 1,
+                reason: TransferError::MisalignedLength {
+                    len: 3,
+                    bits_per_word: 12
+                }
+            })
+        );
+        assert!(spi.validate_message(&transfers[..1]).is_ok());
+    }
+
+    #[test]
+    fn test_transfer_words_bits_per_word_mismatch() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        spi.bits_per_word.store(12, Ordering::Relaxed);
+        let mut rx = [0; 2];
+        let err = spi.transfer_words(16, &[1, 2], &mut rx).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.raw_os_error(), None);
+
+        // A matching size gets as far as the ioctl, which /dev/null rejects
+        let err = spi.transfer_words(12, &[1, 2], &mut rx).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+    }
+
+    #[test]
+    fn test_bits_per_word_cache_configure() {
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        spi.bits_per_word.store(12, Ordering::Relaxed);
+        // Options leaving the word size alone keep the cache
+        spi.configure(&SpidevOptions::new().cs_change(true).build())
+            .unwrap();
+        assert_eq!(spi.bits_per_word.load(Ordering::Relaxed), 12);
+
+        // A failed write leaves the word size unknown
+        let options = SpidevOptions::new().bits_per_word(16).build();
+        assert!(spi.configure(&options).is_err());
+        assert_eq!(spi.bits_per_word.load(Ordering::Relaxed), 0);
+
+        // Successful writes are remembered, 0 standing for 8 bits
+        spi.record_bits_per_word(0, true);
+        assert_eq!(spi.bits_per_word.load(Ordering::Relaxed), 8);
+        spi.record_bits_per_word(16, true);
+        assert_eq!(spi.bits_per_word.load(Ordering::Relaxed), 16);
+    }
+
+    #[test]
+    fn test_bits_per_word_cache_detect() {
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        spi.bits_per_word.store(16, Ordering::Relaxed);
+        assert!(spi.detect_bits_per_word(&[0x9f], &[0x12], &[8]).is_err());
+        assert_eq!(spi.bits_per_word.load(Ordering::Relaxed), 0);
+
+        // Probing a candidate that does not fit the command moves on
+        // rather than aborting the detection
+        spi.bits_per_word.store(16, Ordering::Relaxed);
+        let err = spi.write_read(&[0x9f], &mut [0]).unwrap_err();
+        assert!(super::is_misaligned(&err));
+        spi.bits_per_word.store(0, Ordering::Relaxed);
+        let err = spi.write_read(&[0x9f], &mut [0]).unwrap_err();
+        assert!(!super::is_misaligned(&err));
+    }
+
+    #[test]
+    fn test_try_transfer_busy() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        let mut transfer = SpidevTransfer::delay(0);
+
+        // /dev/null rejects the ioctl once the transfer is attempted
+        assert!(spi.try_transfer(&mut transfer).is_err());
+
+        spi.in_flight.fetch_add(1, Ordering::Acquire);
+        assert!(!spi.try_transfer(&mut transfer).unwrap());
+        spi.in_flight.fetch_sub(1, Ordering::Release);
+    }
+
+    #[test]
+    fn test_spidev_send_sync() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<Spidev>();
+        assert_sync::<Spidev>();
+    }
+
+    #[test]
+    fn test_mode_flags_raw() {
+        assert_eq!(
+            SpiModeFlags::try_from_raw(0x203),
+            Ok(SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_TX_QUAD)
+        );
+        assert_eq!(
+            SpiModeFlags::try_from_raw(0x1_0001),
+            Err(super::UnknownModeBits { bits: 0x1_0000 })
+        );
+        assert_eq!(SpiModeFlags::SPI_MODE_3.as_u8(), Some(3));
+        assert_eq!(SpiModeFlags::SPI_RX_DUAL.as_u8(), None);
+        assert_eq!(SpiModeFlags::SPI_TX_OCTAL.as_u8(), None);
+    }
+
+    #[test]
+    fn test_spi_mode_conversions() {
+        use super::{ExtraModeFlags, SpiMode};
+        use std::convert::TryFrom;
+
+        let modes = [
+            SpiMode::Mode0,
+            SpiMode::Mode1,
+            SpiMode::Mode2,
+            SpiMode::Mode3,
+        ];
+        for (number, &mode) in modes.iter().enumerate() {
+            let flags = SpiModeFlags::from(mode);
+            assert_eq!(flags.bits(), number as u32);
+            assert_eq!(SpiMode::try_from(flags), Ok(mode));
+        }
+        assert_eq!(
+            SpiMode::try_from(SpiModeFlags::SPI_MODE_1 | SpiModeFlags::SPI_CS_HIGH),
+            Err(ExtraModeFlags {
+                bits: SpiModeFlags::SPI_CS_HIGH.bits()
+            })
+        );
+
+        let mut options = SpidevOptions::new();
+        options
+            .set_mode_flag(SpiModeFlags::SPI_CS_HIGH, true)
+            .spi_mode(SpiMode::Mode2);
+        assert_eq!(
+            options.spi_mode,
+            Some(SpiModeFlags::SPI_MODE_2 | SpiModeFlags::SPI_CS_HIGH)
+        );
+    }
+
+    #[test]
+    fn test_mode_flags_parse_name() {
+        assert_eq!(
+            SpiModeFlags::parse_name("MODE_0"),
+            Some(SpiModeFlags::SPI_MODE_0)
+        );
+        assert_eq!(
+            SpiModeFlags::parse_name("cs_high"),
+            Some(SpiModeFlags::SPI_CS_HIGH)
+        );
+        assert_eq!(
+            SpiModeFlags::parse_name(" SPI_TX_QUAD "),
+            Some(SpiModeFlags::SPI_TX_QUAD)
+        );
+        assert_eq!(SpiModeFlags::parse_name("MODE_4"), None);
+        assert_eq!(SpiModeFlags::parse_name(""), None);
+    }
+
+    #[test]
+    fn test_spidev_options_mode_names() {
+        let mut options = SpidevOptions::new();
+        options.mode_names("MODE_3, cs_high").unwrap();
+        assert_eq!(
+            options.spi_mode,
+            Some(SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_CS_HIGH)
+        );
+
+        let err = options.mode_names("CPOL,BOGUS").unwrap_err();
+        assert_eq!(err.name, "BOGUS");
+        assert_eq!(
+            options.spi_mode,
+            Some(SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_CS_HIGH)
+        );
+
+        // Replacing a mode32 mode goes back to the 8-bit ioctl
+        let options = SpidevOptions::new()
+            .mode32(SpiModeFlags::SPI_MODE_1)
+            .mode_names("MODE_0")
+            .unwrap()
+            .build();
+        assert!(!options.force_mode32);
+        assert_eq!(
+            options.planned_ioctls(),
+            vec![PlannedIoctl::SetMode(SpiModeFlags::SPI_MODE_0)]
+        );
+    }
+
+    #[test]
+    fn test_spidev_options_all() {
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(20_000)
+            .lsb_first(false)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        assert_eq!(options.bits_per_word, Some(8));
+        assert_eq!(options.max_speed_hz, Some(20_000));
+        assert_eq!(options.lsb_first, Some(false));
+        assert_eq!(options.spi_mode, Some(SpiModeFlags::SPI_MODE_0));
+    }
+
+    #[test]
+    fn test_spidev_options_some() {
+        let mut options = SpidevOptions::new();
+        options.bits_per_word(10);
+        options.lsb_first(true);
+        assert_eq!(options.bits_per_word, Some(10));
+        assert_eq!(options.max_speed_hz, None);
+        assert_eq!(options.lsb_first, Some(true));
+        assert_eq!(options.spi_mode, None);
+    }
+
+    #[test]
+    fn test_spidev_options_max_speed_units() {
+        assert_eq!(
+            SpidevOptions::new().max_speed_khz(500).build().max_speed_hz,
+            Some(500_000)
+        );
+        assert_eq!(
+            SpidevOptions::new().max_speed_mhz(10).build().max_speed_hz,
+            Some(10_000_000)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_spidev_options_max_speed_mhz_overflow() {
+        SpidevOptions::new().max_speed_mhz(5_000);
+    }
+
+    #[test]
+    fn test_spidev_options_changes_from() {
+        let current = SpidevOptions::new()
+            .bits_per_word(0)
+            .max_speed_hz(1_000_000)
+            .lsb_first(false)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        let wanted = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(10_000_000)
+            .lsb_first(false)
+            .mode(SpiModeFlags::SPI_MODE_3)
+            .build();
+        let expected = SpidevOptions::new()
+            .max_speed_hz(10_000_000)
+            .mode(SpiModeFlags::SPI_MODE_3)
+            .build();
+        assert_eq!(wanted.changes_from(&current), expected);
+        assert_eq!(current.changes_from(&current), SpidevOptions::default());
+    }
+
+    #[test]
+    fn test_spidev_options_changes_from_skipped_writes() {
+        let current = SpidevOptions::new()
+            .bits_per_word(0)
+            .max_speed_hz(1_000_000)
+            .lsb_first(false)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        let wanted = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(10_000_000)
+            .lsb_first(false)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        assert_eq!(wanted.planned_ioctls().len(), 4);
+        // Only the speed differs, so three of the four writes are skipped
+        assert_eq!(
+            wanted.changes_from(&current).planned_ioctls(),
+            vec![PlannedIoctl::SetMaxSpeedHz(10_000_000)]
+        );
+        assert!(current.changes_from(&current).planned_ioctls().is_empty());
+    }
+
+    #[test]
+    fn test_spidev_options_eq() {
+        let loaded = SpidevOptions::new()
+            .max_speed_hz(1_000_000)
+            .mode(SpiModeFlags::SPI_MODE_1)
+            .build();
+        let applied = SpidevOptions::new()
+            .mode(SpiModeFlags::SPI_MODE_1)
+            .max_speed_hz(1_000_000)
+            .build();
+        assert_eq!(loaded, applied);
+        assert_ne!(loaded, SpidevOptions::new().max_speed_hz(1_000_000).build());
+        assert_ne!(
+            loaded,
+            SpidevOptions::new()
+                .max_speed_hz(1_000_000)
+                .mode(SpiModeFlags::SPI_MODE_1 | SpiModeFlags::SPI_CS_HIGH)
+                .build()
+        );
+    }
+
+    #[test]
+    fn test_spidev_options_plausible() {
+        let caps = SpiCapabilities {
+            max_speed_hz: Some(10_000_000),
+            ..SpiCapabilities::default()
+        };
+        let fast = SpidevOptions::new().max_speed_hz(20_000_000).build();
+        assert!(!fast.plausible(Some(&caps), true, None));
+        assert!(fast.plausible(None, true, None));
+        assert!(!SpidevOptions::new()
+            .max_speed_hz(0)
+            .build()
+            .plausible(None, true, None));
+
+        let quad = SpidevOptions::new()
+            .mode(SpiModeFlags::SPI_MODE_0 | SpiModeFlags::SPI_TX_QUAD)
+            .build();
+        assert!(quad.plausible(None, true, None));
+        assert!(!quad.plausible(None, false, None));
+        assert!(SpidevOptions::new()
+            .mode(SpiModeFlags::SPI_MODE_3)
+            .build()
+            .plausible(None, false, None));
+
+        let octal = SpidevOptions::new()
+            .mode(SpiModeFlags::SPI_RX_OCTAL)
+            .build();
+        assert!(!octal.plausible(None, true, Some((4, 19))));
+        assert!(octal.plausible(None, true, Some((5, 1))));
+        assert!(octal.plausible(None, true, None));
+    }
+
+    #[test]
+    fn test_can_configure_not_spidev() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        assert!(spi.can_configure(&SpidevOptions::new()).is_err());
+    }
+
+    #[test]
+    fn test_planned_ioctls() {
+        let options = SpidevOptions::new()
+            .max_speed_hz(1_000_000)
+            .bits_per_word(8)
+            .mode(SpiModeFlags::SPI_MODE_1)
+            .build();
+        assert_eq!(
+            options.planned_ioctls(),
+            vec![
+                PlannedIoctl::SetBitsPerWord(8),
+                PlannedIoctl::SetMaxSpeedHz(1_000_000),
+                PlannedIoctl::SetMode(SpiModeFlags::SPI_MODE_1),
+            ]
+        );
+        assert_eq!(options.planned_ioctls()[2].name(), "SPI_IOC_WR_MODE");
+        assert_eq!(
+            SpidevOptions::new()
+                .mode32(SpiModeFlags::SPI_MODE_0)
+                .build()
+                .planned_ioctls(),
+            vec![PlannedIoctl::SetMode32(SpiModeFlags::SPI_MODE_0)]
+        );
+        assert!(SpidevOptions::new().planned_ioctls().is_empty());
+        // cs_change is applied by the crate, not the kernel
+        assert!(SpidevOptions::new()
+            .cs_change(true)
+            .build()
+            .planned_ioctls()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_spidev_options_validate() {
+        assert_eq!(
+            SpidevOptions::new()
+                .bits_per_word(32)
+                .lsb_first(false)
+                .mode(SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_TX_QUAD)
+                .validate(),
+            Ok(())
+        );
+        assert_eq!(
+            SpidevOptions::new()
+                .bits_per_word(33)
+                .max_speed_hz(0)
+                .lsb_first(true)
+                .mode(SpiModeFlags::from_bits_retain(0x8000_0000))
+                .validate(),
+            Err(vec![
+                OptionError::BitsPerWordTooLarge { bits_per_word: 33 },
+                OptionError::ZeroSpeed,
+                OptionError::UnknownModeBits { bits: 0x8000_0000 },
+                OptionError::ConflictingLsbFirst,
+            ])
+        );
+        assert_eq!(
+            SpidevOptions::new()
+                .mode(
+                    SpiModeFlags::SPI_3WIRE | SpiModeFlags::SPI_RX_DUAL | SpiModeFlags::SPI_RX_QUAD
+                )
+                .validate(),
+            Err(vec![
+                OptionError::ConflictingBusWidth,
+                OptionError::ThreeWireMultiBit,
+            ])
+        );
+        assert_eq!(
+            SpidevOptions::new()
+                .mode(SpiModeFlags::SPI_TX_QUAD | SpiModeFlags::SPI_TX_OCTAL)
+                .validate(),
+            Err(vec![OptionError::ConflictingBusWidth])
+        );
+        assert_eq!(
+            SpidevOptions::new()
+                .mode(SpiModeFlags::SPI_TX_OCTAL | SpiModeFlags::SPI_RX_OCTAL)
+                .validate(),
+            Ok(())
+        );
+        assert_eq!(
+            SpidevOptions::new()
+                .cs_setup_hold(Duration::from_micros(10), Duration::from_millis(70))
+                .validate(),
+            Err(vec![OptionError::CsDelayTooLong {
+                delay: Duration::from_millis(70)
+            }])
+        );
+    }
+
+    #[test]
+    fn test_spidev_options_set_mode_flag() {
+        let options = SpidevOptions::new()
+            .mode(SpiModeFlags::SPI_MODE_3)
+            .set_mode_flag(SpiModeFlags::SPI_CS_HIGH, true)
+            .set_mode_flag(SpiModeFlags::SPI_CPHA, false)
+            .build();
+        assert_eq!(
+            options.spi_mode,
+            Some(SpiModeFlags::SPI_CPOL | SpiModeFlags::SPI_CS_HIGH)
+        );
+        assert_eq!(
+            SpidevOptions::new()
+                .set_mode_flag(SpiModeFlags::SPI_NO_CS, true)
+                .build()
+                .spi_mode,
+            Some(SpiModeFlags::SPI_NO_CS)
+        );
+    }
+
+    #[test]
+    fn test_spidev_options_mode32() {
+        let mut options = SpidevOptions::new();
+        options.mode32(SpiModeFlags::SPI_RX_QUAD);
+        assert_eq!(options.spi_mode, Some(SpiModeFlags::SPI_RX_QUAD));
+        assert!(options.force_mode32);
+
+        options.mode(SpiModeFlags::SPI_MODE_0);
+        assert!(!options.force_mode32);
+    }
+
+    #[test]
+    fn test_spidev_options_mode_number() {
+        let mut options = SpidevOptions::new();
+        options.mode_number(3);
+        assert_eq!(options.spi_mode, Some(SpiModeFlags::SPI_MODE_3));
+
+        options.mode(SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_CS_HIGH);
+        options.mode_number(1);
+        assert_eq!(
+            options.spi_mode,
+            Some(SpiModeFlags::SPI_MODE_1 | SpiModeFlags::SPI_CS_HIGH)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_spidev_options_mode_number_invalid() {
+        SpidevOptions::new().mode_number(4);
     }
 }
@@ -0,0 +1,228 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A mock spidev for testing drivers without hardware
+//!
+//! [`MockSpidev`] mirrors the transfer and configuration methods of
+//! [`Spidev`](crate::Spidev), recording the data written by each
+//! transfer and replaying scripted responses into receive buffers.
+//! This module requires the `mock` feature.
+//!
+//! ```
+//! use spidev::mock::MockSpidev;
+//!
+//! let spi = MockSpidev::new();
+//! spi.push_response(&[0xef, 0x40, 0x18]);
+//!
+//! let mut id = [0; 3];
+//! spi.write_read(&[0x9f], &mut id).unwrap();
+//! assert_eq!(id, [0xef, 0x40, 0x18]);
+//! assert_eq!(spi.written(), vec![vec![0x9f]]);
+//! ```
+
+use super::{SpiDevice, SpidevOptions, SpidevTransfer};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+
+/// An in-memory stand-in for [`Spidev`](crate::Spidev)
+///
+/// Every transfer with a transmit buffer appends its data to the
+/// record returned by [`written`](MockSpidev::written).  Every
+/// transfer with a receive buffer consumes the next response queued
+/// with [`push_response`](MockSpidev::push_response), which must have
+/// the same length as the buffer.
+#[derive(Debug, Default)]
+pub struct MockSpidev {
+    state: RefCell<MockState>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    written: Vec<Vec<u8>>,
+    responses: VecDeque<Vec<u8>>,
+    options: SpidevOptions,
+}
+
+impl MockSpidev {
+    /// Create a mock with no recorded data and no queued responses
+    pub fn new() -> Self {
+        MockSpidev::default()
+    }
+
+    /// Queue the data received by the next transfer with a receive buffer
+    pub fn push_response(&self, data: &[u8]) {
+        self.state.borrow_mut().responses.push_back(data.to_vec());
+    }
+
+    /// The data written by each transfer so far, in order
+    pub fn written(&self) -> Vec<Vec<u8>> {
+        self.state.borrow().written.clone()
+    }
+
+    /// Return the data written so far and clear the record
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.state.borrow_mut().written)
+    }
+
+    /// Number of queued responses not consumed yet
+    pub fn pending_responses(&self) -> usize {
+        self.state.borrow().responses.len()
+    }
+
+    /// Merge the options present in `options` into the mock configuration
+    pub fn configure(&mut self, options: &SpidevOptions) -> io::Result<()> {
+        let current = &mut self.state.get_mut().options;
+        if options.bits_per_word.is_some() {
+            current.bits_per_word = options.bits_per_word;
+        }
+        if options.max_speed_hz.is_some() {
+            current.max_speed_hz = options.max_speed_hz;
+        }
+        if options.lsb_first.is_some() {
+            current.lsb_first = options.lsb_first;
+        }
+        if options.spi_mode.is_some() {
+            current.spi_mode = options.spi_mode;
+            current.force_mode32 = options.force_mode32;
+        }
+        if options.cs_change.is_some() {
+            current.cs_change = options.cs_change;
+        }
+        if options.reset_on_drop.is_some() {
+            current.reset_on_drop = options.reset_on_drop;
+        }
+        if options.cs_setup_hold.is_some() {
+            current.cs_setup_hold = options.cs_setup_hold;
+        }
+        Ok(())
+    }
+
+    /// The configuration accumulated by calls to `configure`
+    pub fn query_configuration(&self) -> io::Result<SpidevOptions> {
+        Ok(self.state.borrow().options)
+    }
+
+    /// Perform a single transfer
+    pub fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        self.transfer_multiple(std::slice::from_mut(transfer))
+    }
+
+    /// Perform multiple transfers as one message
+    ///
+    /// Nothing is recorded or consumed if not enough responses are
+    /// queued for the message, or if one of them has the wrong length.
+    pub fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+
+        let mut responses = state.responses.iter();
+        for transfer in transfers.iter_mut() {
+            if let Some(rx) = transfer.rx_slice_mut() {
+                let response = responses.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "no response queued")
+                })?;
+                if response.len() != rx.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "queued response of {} bytes does not fit a {} byte read",
+                            response.len(),
+                            rx.len()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        for transfer in transfers.iter_mut() {
+            if let Some(tx) = transfer.tx_slice() {
+                let tx = tx.to_vec();
+                state.written.push(tx);
+            }
+            if let Some(rx) = transfer.rx_slice_mut() {
+                let response = state.responses.pop_front().unwrap();
+                rx.copy_from_slice(&response);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `tx` and then read into `rx` within a single message
+    pub fn write_read(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        let mut transfers = [SpidevTransfer::write(tx), SpidevTransfer::read(rx)];
+        self.transfer_multiple(&mut transfers)
+    }
+}
+
+impl SpiDevice for MockSpidev {
+    fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        MockSpidev::transfer(self, transfer)
+    }
+
+    fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        MockSpidev::transfer_multiple(self, transfers)
+    }
+
+    fn write_read(&self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        MockSpidev::write_read(self, tx, rx)
+    }
+
+    fn configure(&mut self, options: &SpidevOptions) -> io::Result<()> {
+        MockSpidev::configure(self, options)
+    }
+
+    fn query_configuration(&self) -> io::Result<SpidevOptions> {
+        MockSpidev::query_configuration(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mock_records_and_replays() {
+        let spi = MockSpidev::new();
+        spi.push_response(&[0xaa, 0xbb]);
+
+        let tx_buf = [0x01, 0x02];
+        let mut rx_buf = [0; 2];
+        let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+        spi.transfer(&mut transfer).unwrap();
+
+        assert_eq!(rx_buf, [0xaa, 0xbb]);
+        assert_eq!(spi.take_written(), vec![vec![0x01, 0x02]]);
+        assert!(spi.written().is_empty());
+    }
+
+    #[test]
+    fn test_mock_missing_response() {
+        let spi = MockSpidev::new();
+        let mut rx_buf = [0; 1];
+        let err = spi.write_read(&[0x05], &mut rx_buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(spi.written().is_empty());
+
+        spi.push_response(&[1, 2]);
+        let err = spi.write_read(&[0x05], &mut rx_buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(spi.pending_responses(), 1);
+    }
+
+    #[test]
+    fn test_mock_configure() {
+        let mut spi = MockSpidev::new();
+        spi.configure(&SpidevOptions::new().max_speed_hz(1000).build())
+            .unwrap();
+        spi.configure(&SpidevOptions::new().bits_per_word(8).build())
+            .unwrap();
+        let options = spi.query_configuration().unwrap();
+        assert_eq!(options.max_speed_hz, Some(1000));
+        assert_eq!(options.bits_per_word, Some(8));
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{Spidev, SpidevTransfer};
+use std::io;
+use std::panic;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+/// A full-duplex transfer owning its buffers, for repeated use
+///
+/// The transmit and receive buffers are allocated once and reused for
+/// every `run`, along with the per-transfer settings, so sampling
+/// loops do not allocate.
+#[derive(Debug)]
+pub struct ReusableTransfer {
+    tx: Box<[u8]>,
+    rx: Box<[u8]>,
+    speed_hz: u32,
+    bits_per_word: u8,
+}
+
+impl ReusableTransfer {
+    /// Allocate a transfer of `len` bytes, initially sending zeroes
+    pub fn new(len: usize) -> ReusableTransfer {
+        ReusableTransfer {
+            tx: vec![0; len].into_boxed_slice(),
+            rx: vec![0; len].into_boxed_slice(),
+            speed_hz: 0,
+            bits_per_word: 0,
+        }
+    }
+
+    /// Number of bytes transferred by each `run`
+    pub fn len(&self) -> usize {
+        self.tx.len()
+    }
+
+    /// Whether the transfer moves no data
+    pub fn is_empty(&self) -> bool {
+        self.tx.is_empty()
+    }
+
+    /// The data sent by the next `run`
+    pub fn tx_mut(&mut self) -> &mut [u8] {
+        &mut self.tx
+    }
+
+    /// The data received by the last `run`
+    pub fn rx(&self) -> &[u8] {
+        &self.rx
+    }
+
+    /// Override the clock speed for this transfer, 0 meaning the default
+    pub fn set_speed_hz(&mut self, speed_hz: u32) {
+        self.speed_hz = speed_hz;
+    }
+
+    /// Override the word size for this transfer, 0 meaning the default
+    pub fn set_bits_per_word(&mut self, bits_per_word: u8) {
+        self.bits_per_word = bits_per_word;
+    }
+
+    /// Perform the transfer on `spi`, returning the received data
+    pub fn run(&mut self, spi: &Spidev) -> io::Result<&[u8]> {
+        let mut transfer = SpidevTransfer::read_write(&self.tx, &mut self.rx);
+        transfer.speed_hz = self.speed_hz;
+        transfer.bits_per_word = self.bits_per_word;
+        spi.transfer(&mut transfer)?;
+        Ok(&self.rx)
+    }
+}
+
+/// Result of a transfer running in the background
+type Outcome = (ReusableTransfer, io::Result<()>);
+
+/// A transfer started with `Spidev::transfer_async`
+///
+/// The handle owns the transfer until it completes; `wait` hands it
+/// back with the received data.  Dropping the handle does not cancel
+/// the transfer, which still runs to completion in the background.
+#[derive(Debug)]
+pub struct TransferHandle {
+    receiver: Receiver<Outcome>,
+    worker: Option<JoinHandle<()>>,
+    outcome: Option<Outcome>,
+}
+
+impl TransferHandle {
+    pub(crate) fn spawn(spi: Spidev, mut transfer: ReusableTransfer) -> TransferHandle {
+        let (sender, receiver) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            let result = transfer.run(&spi).map(|_| ());
+            // The receiver is gone if the handle was dropped
+            let _ = sender.send((transfer, result));
+        });
+        TransferHandle {
+            receiver,
+            worker: Some(worker),
+            outcome: None,
+        }
+    }
+
+    /// Whether the transfer has completed, successfully or not
+    pub fn is_done(&mut self) -> bool {
+        if self.outcome.is_none() {
+            match self.receiver.try_recv() {
+                Ok(outcome) => self.outcome = Some(outcome),
+                Err(TryRecvError::Empty) => return false,
+                // The worker panicked, which `wait` reports
+                Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        true
+    }
+
+    /// Block until the transfer completes and return it with its result
+    ///
+    /// The transfer is handed back whether or not it succeeded, so its
+    /// buffers can be reused either way.  On success the received data
+    /// is available from its `rx`.  A panic of the worker thread is
+    /// propagated.
+    pub fn wait(mut self) -> (ReusableTransfer, io::Result<()>) {
+        let outcome = match self.outcome.take() {
+            Some(outcome) => Ok(outcome),
+            None => self.receiver.recv(),
+        };
+        match outcome {
+            Ok(outcome) => outcome,
+            // The worker only drops the sender without sending by panicking
+            Err(_) => match self.worker.take().map(JoinHandle::join) {
+                Some(Err(payload)) => panic::resume_unwind(payload),
+                _ => unreachable!("transfer worker exited without a result"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffers() {
+        let mut reusable = ReusableTransfer::new(3);
+        reusable.tx_mut().copy_from_slice(&[1, 2, 3]);
+        assert_eq!(reusable.len(), 3);
+        assert_eq!(reusable.rx(), &[0, 0, 0]);
+
+        // settings are kept for every run
+        reusable.set_speed_hz(1_000_000);
+        reusable.set_bits_per_word(16);
+        assert_eq!((reusable.speed_hz, reusable.bits_per_word), (1_000_000, 16));
+    }
+
+    #[test]
+    fn test_transfer_async_error() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        let mut handle = spi.transfer_async(ReusableTransfer::new(2)).unwrap();
+        while !handle.is_done() {
+            thread::yield_now();
+        }
+        let (mut transfer, result) = handle.wait();
+        assert!(result.is_err());
+        // The buffers come back for reuse despite the failure
+        assert_eq!(transfer.len(), 2);
+        transfer.tx_mut()[0] = 1;
+    }
+
+    #[test]
+    fn test_run_error() {
+        let spi = Spidev::open("/dev/null").unwrap();
+        let mut reusable = ReusableTransfer::new(2);
+        assert!(reusable.run(&spi).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{Spidev, SpidevTransfer, TransferError};
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+
+/// Builder for the transfers of a single message
+///
+/// ```no_run
+/// # use spidev::{Spidev, TransferSequence};
+/// # use std::time::Duration;
+/// # fn main() -> std::io::Result<()> {
+/// let spi = Spidev::open("/dev/spidev0.0")?;
+/// let mut status = [0u8; 2];
+/// TransferSequence::new()
+///     .write(&[0x9f])
+///     .delay_after(Duration::from_micros(10))?
+///     .read(&mut status)
+///     .run(&spi)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Delays
+///
+/// `delay_after` sets the `delay_usecs` field of the preceding
+/// transfer: the controller waits after clocking its last bit, before
+/// the chip select is toggled (if `cs_change` is set) and the next
+/// transfer starts.  Inserting a `SpidevTransfer::delay` instead
+/// achieves the same with an extra, zero-length transfer, which some
+/// controllers handle less efficiently and which counts towards the
+/// limit on transfers per message.  A standalone delay is only needed
+/// at the very start of a message, where there is no transfer to
+/// attach it to.
+#[derive(Debug, Default)]
+pub struct TransferSequence<'a, 'b> {
+    transfers: Vec<SpidevTransfer<'a, 'b>>,
+}
+
+impl<'a, 'b> TransferSequence<'a, 'b> {
+    /// Start an empty sequence
+    pub fn new() -> Self {
+        TransferSequence {
+            transfers: Vec::new(),
+        }
+    }
+
+    /// Append a transfer
+    pub fn push(mut self, transfer: SpidevTransfer<'a, 'b>) -> Self {
+        self.transfers.push(transfer);
+        self
+    }
+
+    /// Append a write transfer
+    pub fn write(self, buf: &'a [u8]) -> Self {
+        self.push(SpidevTransfer::write(buf))
+    }
+
+    /// Append a read transfer
+    pub fn read(self, buf: &'b mut [u8]) -> Self {
+        self.push(SpidevTransfer::read(buf))
+    }
+
+    /// Wait for `delay` after the preceding transfer
+    ///
+    /// Delays add up if the preceding transfer already has one.  On an
+    /// empty sequence a zero-length delay transfer is appended instead.
+    /// Fails if the total does not fit the 16-bit microsecond field.
+    pub fn delay_after(mut self, delay: Duration) -> Result<Self, TransferError> {
+        match self.transfers.last_mut() {
+            Some(last) => {
+                let total = u128::from(last.delay_usecs) + delay.as_micros();
+                last.delay_usecs =
+                    u16::try_from(total).map_err(|_| TransferError::DelayTooLong {
+                        delay: Duration::from_micros(last.delay_usecs.into()) + delay,
+                    })?;
+                Ok(self)
+            }
+            None => Ok(self.push(SpidevTransfer::delay_duration(delay)?)),
+        }
+    }
+
+    /// Number of transfers in the sequence
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Whether the sequence holds no transfers
+    pub fn is_empty(&self) -> bool {
+        self.transfers.is_empty()
+    }
+
+    /// The transfers, e.g. to adjust them before sending
+    pub fn as_mut_slice(&mut self) -> &mut [SpidevTransfer<'a, 'b>] {
+        &mut self.transfers
+    }
+
+    /// Send the sequence as one message with `Spidev::transfer_multiple`
+    pub fn run(mut self, spi: &Spidev) -> io::Result<()> {
+        spi.transfer_multiple(&mut self.transfers)
+    }
+
+    /// Take the transfers out of the sequence
+    pub fn into_vec(self) -> Vec<SpidevTransfer<'a, 'b>> {
+        self.transfers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay_after() {
+        let tx_buf = [1u8, 2];
+        let mut rx_buf = [0u8; 2];
+        let sequence = TransferSequence::new()
+            .write(&tx_buf)
+            .delay_after(Duration::from_micros(10))
+            .unwrap()
+            .delay_after(Duration::from_micros(5))
+            .unwrap()
+            .read(&mut rx_buf);
+        assert_eq!(sequence.len(), 2);
+
+        let transfers = sequence.into_vec();
+        assert_eq!(transfers[0].delay_usecs, 15);
+        assert_eq!(transfers[1].delay_usecs, 0);
+    }
+
+    #[test]
+    fn test_delay_after_empty() {
+        let sequence = TransferSequence::new()
+            .delay_after(Duration::from_micros(20))
+            .unwrap();
+        let transfers = sequence.into_vec();
+        assert_eq!(transfers.len(), 1);
+        assert!(transfers[0].is_empty());
+        assert_eq!(transfers[0].delay_usecs, 20);
+    }
+
+    #[test]
+    fn test_delay_after_too_long() {
+        let tx_buf = [0u8];
+        let sequence = TransferSequence::new()
+            .write(&tx_buf)
+            .delay_after(Duration::from_micros(60_000))
+            .unwrap();
+        assert!(matches!(
+            sequence.delay_after(Duration::from_micros(10_000)),
+            Err(TransferError::DelayTooLong { .. })
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Serde support for `SpiModeFlags`
+//!
+//! Mode flags are serialized as a list of flag names, such as
+//! `["SPI_CPOL", "SPI_CS_HIGH"]`, falling back to the raw integer
+//! when bits without a name are set.  They may be deserialized from
+//! either form, and also from a single flag name.  Since the integer
+//! values of `SPI_MODE_0` through `SPI_MODE_3` are 0 through 3, a
+//! plain mode number is accepted as well.
+
+use super::SpiModeFlags;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+
+impl Serialize for SpiModeFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if SpiModeFlags::from_bits(self.bits()).is_none() {
+            return serializer.serialize_u32(self.bits());
+        }
+        serializer.collect_seq(self.iter_names().map(|(name, _)| name))
+    }
+}
+
+struct SpiModeFlagsVisitor;
+
+impl SpiModeFlagsVisitor {
+    fn from_bits<E: de::Error>(bits: u32) -> Result<SpiModeFlags, E> {
+        SpiModeFlags::from_bits(bits)
+            .ok_or_else(|| E::custom(format_args!("unknown SPI mode bits {:#x}", bits)))
+    }
+
+    fn from_name<E: de::Error>(name: &str) -> Result<SpiModeFlags, E> {
+        SpiModeFlags::from_name(name)
+            .ok_or_else(|| E::custom(format_args!("unknown SPI mode flag `{}`", name)))
+    }
+}
+
+impl<'de> Visitor<'de> for SpiModeFlagsVisitor {
+    type Value = SpiModeFlags;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a SPI mode number, flag name or list of flag names")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<SpiModeFlags, E> {
+        let bits = u32::try_from(value)
+            .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))?;
+        Self::from_bits(bits)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<SpiModeFlags, E> {
+        let bits = u32::try_from(value)
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))?;
+        Self::from_bits(bits)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<SpiModeFlags, E> {
+        Self::from_name(value)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<SpiModeFlags, A::Error> {
+        let mut mode = SpiModeFlags::empty();
+        while let Some(name) = seq.next_element::<String>()? {
+            mode |= Self::from_name(&name)?;
+        }
+        Ok(mode)
+    }
+}
+
+impl<'de> Deserialize<'de> for SpiModeFlags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(SpiModeFlagsVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::SpidevOptions;
+    use super::*;
+
+    #[test]
+    fn test_mode_flags_serialize_names() {
+        let mode = SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_CS_HIGH;
+        assert_eq!(
+            serde_json::to_string(&mode).unwrap(),
+            r#"["SPI_CPHA","SPI_CPOL","SPI_CS_HIGH"]"#
+        );
+    }
+
+    #[test]
+    fn test_mode_flags_deserialize_forms() {
+        let from_number: SpiModeFlags = serde_json::from_str("2").unwrap();
+        assert_eq!(from_number, SpiModeFlags::SPI_MODE_2);
+        let from_name: SpiModeFlags = serde_json::from_str(r#""SPI_MODE_1""#).unwrap();
+        assert_eq!(from_name, SpiModeFlags::SPI_MODE_1);
+        let from_list: SpiModeFlags =
+            serde_json::from_str(r#"["SPI_MODE_3", "SPI_RX_QUAD"]"#).unwrap();
+        assert_eq!(
+            from_list,
+            SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_RX_QUAD
+        );
+        assert!(serde_json::from_str::<SpiModeFlags>(r#"["SPI_BOGUS"]"#).is_err());
+        assert!(serde_json::from_str::<SpiModeFlags>("65536").is_err());
+    }
+
+    #[test]
+    fn test_options_round_trip() {
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(20_000)
+            .lsb_first(false)
+            .mode(SpiModeFlags::SPI_MODE_1 | SpiModeFlags::SPI_TX_DUAL)
+            .build();
+        let json = serde_json::to_string(&options).unwrap();
+        let decoded: SpidevOptions = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, options);
+
+        let partial: SpidevOptions = serde_json::from_str(r#"{"max_speed_hz": 1000}"#).unwrap();
+        assert_eq!(partial, SpidevOptions::new().max_speed_hz(1000).build());
+    }
+}
@@ -6,12 +6,54 @@
 // option.  This file may not be copied, modified, or distributed
 // except according to those terms.
 
+//! Low-level interface to the spidev ioctls
+//!
+//! Every function here takes the raw file descriptor of an open spidev
+//! node and issues a single ioctl on it, so this module can be used
+//! with file descriptors owned by something other than [`Spidev`],
+//! e.g. an `OwnedFd` from `rustix`, by passing `as_raw_fd()`.  The
+//! naming follows the ioctls: `get_*` for `SPI_IOC_RD_*`, `set_*` for
+//! `SPI_IOC_WR_*`, and `transfer`/`transfer_multiple` for
+//! `SPI_IOC_MESSAGE`.
+//!
+//! Compared to [`Spidev`], nothing is added on top of the kernel's
+//! behavior: interrupted calls are not retried (see
+//! [`retry_on_eintr`]), errors are the plain `errno` values, and
+//! transfers are not checked against the word size or split to fit
+//! `bufsiz`.
+//!
+//! [`Spidev`]: crate::Spidev
+
 // macros import
-use super::SpiModeFlags;
-use nix::{ioctl_read, ioctl_write_buf, ioctl_write_ptr};
+use super::{SpiModeFlags, TransferError};
+use libc::c_ulong;
+use nix::errno::Errno;
+use nix::sys::ioctl::SIZEBITS;
+use nix::{ioctl_read, ioctl_write_ptr, request_code_write};
+use std::convert::TryFrom;
 use std::io;
 use std::marker::PhantomData;
+use std::mem::{align_of, size_of, size_of_val, MaybeUninit};
 use std::os::unix::prelude::*;
+use std::time::Duration;
+
+/// Pass the result of an ioctl through, tracing it if `log` is enabled
+///
+/// The description of the argument is only evaluated when logging.
+macro_rules! traced {
+    ($op:expr, $fd:expr, $result:expr) => {
+        traced!($op, $fd, "", $result)
+    };
+    ($op:expr, $fd:expr, $arg:expr, $result:expr) => {{
+        let result = $result;
+        #[cfg(feature = "log")]
+        match &result {
+            Ok(value) => log::trace!("{}({}) on fd {} -> {:?}", $op, $arg, $fd, value),
+            Err(err) => log::debug!("{}({}) on fd {} failed: {}", $op, $arg, $fd, err),
+        }
+        result
+    }};
+}
 
 fn from_nix_result<T>(res: ::nix::Result<T>) -> io::Result<T> {
     match res {
@@ -36,6 +78,13 @@ fn from_nix_result<T>(res: ::nix::Result<T>) -> io::Result<T> {
 /// @delay_usecs: If nonzero, how long to delay after the last bit transfer
 ///      before optionally deselecting the device before the next transfer.
 /// @cs_change: True to deselect device before starting the next transfer.
+/// @tx_nbits: Number of bits used for writing. If 0 the default
+///      (SPI_NBITS_SINGLE) is used.
+/// @rx_nbits: Number of bits used for reading. If 0 the default
+///      (SPI_NBITS_SINGLE) is used.
+/// @word_delay_usecs: If nonzero, how long to wait between words within
+///      one transfer. This property needs explicit support in the SPI
+///      controller, otherwise it is silently ignored.
 ///
 /// This structure is mapped directly to the kernel spi_transfer structure;
 /// the fields have the same meanings, except of course that the pointers
@@ -70,13 +119,32 @@ pub struct spi_ioc_transfer<'a, 'b> {
     pub speed_hz: u32,
     pub delay_usecs: u16,
     pub bits_per_word: u8,
+    /// Deselect the device after this transfer
+    ///
+    /// For any transfer but the last one of a message, a non-zero
+    /// value deselects the chip *after* the transfer completes (and
+    /// after `delay_usecs`), reselecting it for the next transfer.
+    /// For the last transfer of a message the meaning is inverted: a
+    /// non-zero value leaves the chip selected after the message,
+    /// which is only a hint that the controller may ignore.
     pub cs_change: u8,
-    pub pad: u32,
+    // per-transfer bus widths, zero selects single wire
+    tx_nbits: u8,
+    rx_nbits: u8,
+    pub word_delay_usecs: u8,
+    pub pad: u8,
 
     tx_buf_ref: PhantomData<&'a [u8]>,
     rx_buf_ref: PhantomData<&'b mut [u8]>,
 }
 
+// The kernel reads the structure in place, so its layout is ABI.  The
+// buffer pointers are always carried in 64-bit fields, making it the
+// same 32 bytes on every architecture; these fail to compile otherwise.
+// Field offsets are checked by `test_transfer_layout`.
+const _: [(); 32] = [(); size_of::<spi_ioc_transfer>()];
+const _: [(); 8] = [(); align_of::<spi_ioc_transfer>()];
+
 impl<'a, 'b> spi_ioc_transfer<'a, 'b> {
     /// Create a read transfer
     pub fn read(buff: &'b mut [u8]) -> Self {
@@ -87,6 +155,26 @@ impl<'a, 'b> spi_ioc_transfer<'a, 'b> {
         }
     }
 
+    /// Create a read transfer which shifts out `fill` bytes
+    ///
+    /// Without a transmit buffer the kernel shifts out zeroes, but some
+    /// devices want 0xFF (or another value) while they are being read.
+    /// Rather than allocating a dummy buffer, `buff` is filled with
+    /// `fill` and used as the transmit buffer as well: spidev copies
+    /// the data to send before receiving into the same memory.
+    pub fn read_with_fill(buff: &'b mut [u8], fill: u8) -> Self {
+        for byte in buff.iter_mut() {
+            *byte = fill;
+        }
+        let ptr = buff.as_ptr() as *const () as usize as u64;
+        spi_ioc_transfer {
+            tx_buf: ptr,
+            rx_buf: ptr,
+            len: buff.len() as u32,
+            ..Default::default()
+        }
+    }
+
     /// Create a write transfer
     pub fn write(buff: &'a [u8]) -> Self {
         spi_ioc_transfer {
@@ -98,6 +186,11 @@ impl<'a, 'b> spi_ioc_transfer<'a, 'b> {
 
     /// Create a read/write transfer.
     /// Note that the `tx_buf` and `rx_buf` must be the same length.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buffers differ in length; see `try_read_write`
+    /// for a fallible alternative.
     pub fn read_write(tx_buf: &'a [u8], rx_buf: &'b mut [u8]) -> Self {
         assert_eq!(tx_buf.len(), rx_buf.len());
         spi_ioc_transfer {
@@ -108,6 +201,17 @@ impl<'a, 'b> spi_ioc_transfer<'a, 'b> {
         }
     }
 
+    /// Create a read/write transfer, failing if the buffer lengths differ
+    pub fn try_read_write(tx_buf: &'a [u8], rx_buf: &'b mut [u8]) -> Result<Self, TransferError> {
+        if tx_buf.len() != rx_buf.len() {
+            return Err(TransferError::LengthMismatch {
+                tx_len: tx_buf.len(),
+                rx_len: rx_buf.len(),
+            });
+        }
+        Ok(Self::read_write(tx_buf, rx_buf))
+    }
+
     /// Create a delay transfer of a number of microseconds
     pub fn delay(microseconds: u16) -> Self {
         spi_ioc_transfer {
@@ -116,6 +220,260 @@ impl<'a, 'b> spi_ioc_transfer<'a, 'b> {
             ..Default::default()
         }
     }
+
+    /// Create a delay transfer from a [`Duration`]
+    ///
+    /// The delay is rounded down to whole microseconds.  Unlike
+    /// `delay`, durations which do not fit the 16-bit microsecond
+    /// field (65.535ms) are rejected rather than silently truncated.
+    pub fn delay_duration(delay: Duration) -> Result<Self, TransferError> {
+        let microseconds =
+            u16::try_from(delay.as_micros()).map_err(|_| TransferError::DelayTooLong { delay })?;
+        Ok(Self::delay(microseconds))
+    }
+
+    /// Create an empty transfer which deselects the device
+    ///
+    /// The kernel accepts transfers with a length of zero, performing
+    /// only their side effects (`delay_usecs` and `cs_change`).  When
+    /// placed between other transfers of a message this one releases
+    /// the chip select, separating two logical operations without
+    /// needing a second system call.
+    pub fn cs_toggle() -> Self {
+        spi_ioc_transfer {
+            cs_change: 1,
+            len: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Create a transfer over memory managed by the caller
+    ///
+    /// This lets a transfer point at memory that is not a Rust slice,
+    /// such as a memory-mapped region, without first copying it into
+    /// a `Vec`. A null `tx_ptr` sends zeroes and a null `rx_ptr`
+    /// discards the received data, as with `read` and `write`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` does not fit the 32-bit length of the transfer.
+    ///
+    /// # Safety
+    ///
+    /// Unless null, `tx_ptr` must be valid for reads and `rx_ptr` valid
+    /// for writes of `len` bytes for as long as this transfer is used,
+    /// which includes every transfer call it is passed to. The tx and
+    /// rx regions must not overlap, and the memory behind `rx_ptr` must
+    /// not be accessed through any other pointer while such a call is
+    /// in progress.
+    pub unsafe fn from_raw_parts(tx_ptr: *const u8, rx_ptr: *mut u8, len: usize) -> Self {
+        assert!(len <= u32::MAX as usize, "transfer length overflows u32");
+        spi_ioc_transfer {
+            tx_buf: tx_ptr as usize as u64,
+            rx_buf: rx_ptr as usize as u64,
+            len: len as u32,
+            ..Default::default()
+        }
+    }
+
+    /// Number of bytes moved by this transfer in each direction
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Whether this transfer moves no data, e.g. a delay or `cs_toggle`
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes sent from a transmit buffer
+    #[cfg(feature = "metrics")]
+    pub(crate) fn tx_len(&self) -> usize {
+        if self.tx_buf == 0 {
+            0
+        } else {
+            self.len()
+        }
+    }
+
+    /// Number of bytes stored into a receive buffer
+    #[cfg(feature = "metrics")]
+    pub(crate) fn rx_len(&self) -> usize {
+        if self.rx_buf == 0 {
+            0
+        } else {
+            self.len()
+        }
+    }
+
+    /// View the transmit buffer of this transfer, if it has one
+    pub(crate) fn tx_slice(&self) -> Option<&[u8]> {
+        if self.tx_buf == 0 {
+            return None;
+        }
+        // The pointer and length were taken from a slice borrowed for
+        // 'a, which outlives this transfer, or were promised valid for
+        // reads for as long as it is used by the caller of
+        // `from_raw_parts`
+        Some(unsafe { std::slice::from_raw_parts(self.tx_buf as usize as *const u8, self.len()) })
+    }
+
+    /// View the receive buffer of this transfer, if it has one
+    pub(crate) fn rx_slice_mut(&mut self) -> Option<&mut [u8]> {
+        if self.rx_buf == 0 {
+            return None;
+        }
+        // The pointer and length were taken from a slice mutably
+        // borrowed for 'b, which outlives this transfer, or were
+        // promised valid for writes and unaliased, including by the tx
+        // region, by the caller of `from_raw_parts`
+        Some(unsafe { std::slice::from_raw_parts_mut(self.rx_buf as usize as *mut u8, self.len()) })
+    }
+
+    /// Copy this transfer's settings onto other buffers
+    ///
+    /// The buffers must be as long as this transfer.
+    pub(crate) fn rebind<'c, 'd>(
+        &self,
+        tx_buf: Option<&'c [u8]>,
+        rx_buf: Option<&'d mut [u8]>,
+    ) -> spi_ioc_transfer<'c, 'd> {
+        if let Some(buf) = &tx_buf {
+            debug_assert_eq!(buf.len(), self.len());
+        }
+        if let Some(buf) = &rx_buf {
+            debug_assert_eq!(buf.len(), self.len());
+        }
+        spi_ioc_transfer {
+            tx_buf: tx_buf.map_or(0, |buf| buf.as_ptr() as *const () as usize as u64),
+            rx_buf: rx_buf.map_or(0, |buf| buf.as_ptr() as *const () as usize as u64),
+            len: self.len,
+            speed_hz: self.speed_hz,
+            delay_usecs: self.delay_usecs,
+            bits_per_word: self.bits_per_word,
+            cs_change: self.cs_change,
+            tx_nbits: self.tx_nbits,
+            rx_nbits: self.rx_nbits,
+            word_delay_usecs: self.word_delay_usecs,
+            pad: self.pad,
+            tx_buf_ref: PhantomData,
+            rx_buf_ref: PhantomData,
+        }
+    }
+
+    /// Check the settings that cannot be wrong when built with the
+    /// constructors and builder methods of this type
+    pub(crate) fn check(&self) -> Result<(), TransferError> {
+        for &nbits in [self.tx_nbits, self.rx_nbits].iter() {
+            if nbits != 0 {
+                check_nbits(nbits)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Split this transfer into transfers of at most `size` bytes
+    ///
+    /// Every piece keeps the settings of this transfer, except that
+    /// `delay_usecs` and `cs_change` are only kept on the last one.
+    pub(crate) fn chunks(&self, size: usize) -> Vec<spi_ioc_transfer<'_, '_>> {
+        let len = self.len();
+        (0..len)
+            .step_by(size)
+            .map(|offset| {
+                let mut piece = self.rebind(None, None);
+                if self.tx_buf != 0 {
+                    piece.tx_buf = self.tx_buf + offset as u64;
+                }
+                if self.rx_buf != 0 {
+                    piece.rx_buf = self.rx_buf + offset as u64;
+                }
+                piece.len = size.min(len - offset) as u32;
+                if offset + size < len {
+                    piece.delay_usecs = 0;
+                    piece.cs_change = 0;
+                }
+                piece
+            })
+            .collect()
+    }
+
+    /// Delay between the words of this transfer, in microseconds
+    ///
+    /// Only kernels that know about the `word_delay_usecs` field
+    /// honor it, and only when the controller supports it; otherwise
+    /// it is silently ignored.
+    pub fn with_word_delay_usecs(mut self, microseconds: u8) -> Self {
+        self.word_delay_usecs = microseconds;
+        self
+    }
+
+    /// What happens to the chip select after this transfer
+    ///
+    /// This is for transfers followed by more transfers of the same
+    /// message, where `Deselect` releases the chip before the next
+    /// transfer.  Use `with_final_cs_behavior` for the last transfer,
+    /// where the meaning of `cs_change` is inverted.
+    pub fn with_cs_behavior(mut self, behavior: CsBehavior) -> Self {
+        self.cs_change = behavior.cs_change(false);
+        self
+    }
+
+    /// What happens to the chip select once this last transfer ends
+    /// the message
+    pub fn with_final_cs_behavior(mut self, behavior: CsBehavior) -> Self {
+        self.cs_change = behavior.cs_change(true);
+        self
+    }
+
+    /// Clock speed for this transfer only, in Hz
+    ///
+    /// Zero selects the speed configured for the device.  Unlike
+    /// `SpidevOptions::max_speed_hz`, this does not affect any other
+    /// transfer.
+    pub fn with_speed_hz(mut self, speed_hz: u32) -> Self {
+        self.speed_hz = speed_hz;
+        self
+    }
+
+    /// Number of wires used to transmit this transfer
+    ///
+    /// Must be 1, 2, 4 or 8, and the device must have been configured
+    /// with the matching `SPI_TX_DUAL`/`SPI_TX_QUAD`/`SPI_TX_OCTAL`
+    /// mode bit.  This
+    /// lets a QSPI flash command send its opcode on a single wire and
+    /// its data on four within one message.
+    pub fn with_tx_nbits(mut self, nbits: u8) -> Result<Self, TransferError> {
+        self.tx_nbits = check_nbits(nbits)?;
+        Ok(self)
+    }
+
+    /// Number of wires used to receive this transfer
+    ///
+    /// Must be 1, 2, 4 or 8, and the device must have been configured
+    /// with the matching `SPI_RX_DUAL`/`SPI_RX_QUAD`/`SPI_RX_OCTAL`
+    /// mode bit.
+    pub fn with_rx_nbits(mut self, nbits: u8) -> Result<Self, TransferError> {
+        self.rx_nbits = check_nbits(nbits)?;
+        Ok(self)
+    }
+
+    /// Number of wires used to transmit, 0 meaning the default of one
+    pub fn tx_nbits(&self) -> u8 {
+        self.tx_nbits
+    }
+
+    /// Number of wires used to receive, 0 meaning the default of one
+    pub fn rx_nbits(&self) -> u8 {
+        self.rx_nbits
+    }
+}
+
+fn check_nbits(nbits: u8) -> Result<u8, TransferError> {
+    match nbits {
+        1 | 2 | 4 | 8 => Ok(nbits),
+        _ => Err(TransferError::InvalidNbits { nbits }),
+    }
 }
 
 mod ioctl {
@@ -165,94 +523,570 @@ mod ioctl {
 
     // NOTE: this macro works for single transfers but cannot properly
     // calculate size for multi transfer whose length we will not know
-    // until runtime.  We fallback to using the underlying ioctl for that
-    // use case.
+    // until runtime.  `spidev_transfer_buf` encodes the size of the
+    // whole array, which only fits the size field of the op for up to
+    // `SPI_IOC_MESSAGE_MAX` transfers.
     ioctl_write_ptr!(
         spidev_transfer,
         SPI_IOC_MAGIC,
         SPI_IOC_NR_TRANSFER,
         spi_ioc_transfer
     );
-    ioctl_write_buf!(
-        spidev_transfer_buf,
-        SPI_IOC_MAGIC,
-        SPI_IOC_NR_TRANSFER,
-        spi_ioc_transfer
-    );
+
+    pub unsafe fn spidev_transfer_buf(
+        fd: RawFd,
+        transfers: &mut [spi_ioc_transfer],
+    ) -> ::nix::Result<libc::c_int> {
+        debug_assert!(transfers.len() <= SPI_IOC_MESSAGE_MAX);
+        let size = size_of_val(transfers);
+        let op = request_code_write!(SPI_IOC_MAGIC, SPI_IOC_NR_TRANSFER, size);
+        Errno::result(libc::ioctl(fd, op, transfers.as_mut_ptr()))
+    }
+
+    pub const fn spi_ioc_message(n: usize) -> Option<c_ulong> {
+        if n > SPI_IOC_MESSAGE_MAX {
+            return None;
+        }
+        let size = n * size_of::<spi_ioc_transfer<'static, 'static>>();
+        Some(request_code_write!(SPI_IOC_MAGIC, SPI_IOC_NR_TRANSFER, size) as c_ulong)
+    }
+}
+
+/// Maximum number of transfers in a single message
+///
+/// The size of the transfer array passed to `SPI_IOC_MESSAGE` is
+/// encoded in the size field of the ioctl number, which is 14 bits
+/// wide on most architectures (13 bits on some).  Larger batches
+/// have to be split into several messages.
+pub const SPI_IOC_MESSAGE_MAX: usize =
+    ((1 << SIZEBITS) - 1) / size_of::<spi_ioc_transfer<'static, 'static>>();
+
+/// The ioctl request code of a message of `n` transfers
+///
+/// This is the kernel's `SPI_IOC_MESSAGE(n)`, i.e. `_IOW('k', 0,
+/// struct spi_ioc_transfer[n])`, for use with the raw
+/// [`ioctl_write`] family.  `None` is returned when the array is too
+/// large for the size field of the request code, i.e. for more than
+/// [`SPI_IOC_MESSAGE_MAX`] transfers.
+pub const fn spi_ioc_message(n: usize) -> Option<c_ulong> {
+    ioctl::spi_ioc_message(n)
+}
+
+/// What happens to the chip select after a transfer
+///
+/// The `cs_change` field of a transfer does not say this directly.
+/// Setting it inverts the kernel's default, and the default depends on
+/// where the transfer is in its message:
+///
+/// | Transfer          | `cs_change = 0` | `cs_change = 1`                      |
+/// |-------------------|-----------------|--------------------------------------|
+/// | Followed by more  | stay selected   | deselect before the next transfer    |
+/// | Last of a message | deselect        | stay selected until the next message |
+///
+/// Staying selected after a message is only a hint: if a message for
+/// another device on the same controller comes next, the chip is
+/// deselected first.  [`SpidevTransfer::with_cs_behavior`] and
+/// [`SpidevTransfer::with_final_cs_behavior`] set `cs_change` for the
+/// two positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsBehavior {
+    /// Leave the chip selected
+    KeepSelected,
+    /// Release the chip select
+    Deselect,
+}
+
+impl CsBehavior {
+    /// The `cs_change` value giving this behavior, for the last
+    /// transfer of a message if `last` is set
+    pub fn cs_change(self, last: bool) -> u8 {
+        ((self == CsBehavior::Deselect) != last) as u8
+    }
 }
 
 /// Representation of a spidev transfer that is shared
 /// with external users
 pub type SpidevTransfer<'a, 'b> = spi_ioc_transfer<'a, 'b>;
 
+/// Read the lowest 8 bits of the mode with `SPI_IOC_RD_MODE`
 pub fn get_mode(fd: RawFd) -> io::Result<u8> {
     let mut mode: u8 = 0;
-    from_nix_result(unsafe { ioctl::get_mode_u8(fd, &mut mode) })?;
+    traced!(
+        "SPI_IOC_RD_MODE",
+        fd,
+        from_nix_result(unsafe { ioctl::get_mode_u8(fd, &mut mode) })
+    )?;
     Ok(mode)
 }
 
-pub fn get_mode_u32(fd: RawFd) -> io::Result<u32> {
+/// Read the full 32-bit mode with `SPI_IOC_RD_MODE32`
+///
+/// This ioctl was added in Linux 3.15; older kernels fail with
+/// `ENOTTY`, in which case `get_mode` still works.
+pub fn get_mode32(fd: RawFd) -> io::Result<u32> {
     let mut mode: u32 = 0;
-    from_nix_result(unsafe { ioctl::get_mode_u32(fd, &mut mode) })?;
+    traced!(
+        "SPI_IOC_RD_MODE32",
+        fd,
+        from_nix_result(unsafe { ioctl::get_mode_u32(fd, &mut mode) })
+    )?;
     Ok(mode)
 }
 
+/// Read the full 32-bit mode with `SPI_IOC_RD_MODE32`
+#[deprecated(note = "renamed to `get_mode32`")]
+pub fn get_mode_u32(fd: RawFd) -> io::Result<u32> {
+    get_mode32(fd)
+}
+
+/// Write the mode, using `SPI_IOC_WR_MODE32` only if needed
 pub fn set_mode(fd: RawFd, mode: SpiModeFlags) -> io::Result<()> {
     // we will always use the 8-bit mode write unless bits not in
     // the 8-bit mask are used.  This is because WR_MODE32 was not
     // added until later kernels.  This provides a reasonable story
     // for forwards and backwards compatibility
-    if (mode.bits() & 0xFFFFFF00) != 0 {
-        from_nix_result(unsafe { ioctl::set_mode32(fd, &mode.bits()) })?;
+    if let Some(bits) = mode.as_u8() {
+        traced!(
+            "SPI_IOC_WR_MODE",
+            fd,
+            format_args!("{:#x}", bits),
+            from_nix_result(unsafe { ioctl::set_mode(fd, &bits) })
+        )?;
     } else {
-        let bits: u8 = mode.bits() as u8;
-        from_nix_result(unsafe { ioctl::set_mode(fd, &bits) })?;
+        set_mode32(fd, mode)?;
     }
     Ok(())
 }
 
+/// Write the mode with `SPI_IOC_WR_MODE32`
+pub fn set_mode32(fd: RawFd, mode: SpiModeFlags) -> io::Result<()> {
+    traced!(
+        "SPI_IOC_WR_MODE32",
+        fd,
+        format_args!("{:#x}", mode.bits()),
+        from_nix_result(unsafe { ioctl::set_mode32(fd, &mode.bits()) })
+    )?;
+    Ok(())
+}
+
+/// Read whether words are sent least significant bit first, non-zero
+/// meaning they are
 pub fn get_lsb_first(fd: RawFd) -> io::Result<u8> {
     let mut lsb_first: u8 = 0;
-    from_nix_result(unsafe { ioctl::get_lsb_first(fd, &mut lsb_first) })?;
+    traced!(
+        "SPI_IOC_RD_LSB_FIRST",
+        fd,
+        from_nix_result(unsafe { ioctl::get_lsb_first(fd, &mut lsb_first) })
+    )?;
     Ok(lsb_first)
 }
 
+/// Set whether words are sent least significant bit first
 pub fn set_lsb_first(fd: RawFd, lsb_first: bool) -> io::Result<()> {
     let lsb_first_value: u8 = if lsb_first { 1 } else { 0 };
-    from_nix_result(unsafe { ioctl::set_lsb_first(fd, &lsb_first_value) })?;
+    traced!(
+        "SPI_IOC_WR_LSB_FIRST",
+        fd,
+        lsb_first_value,
+        from_nix_result(unsafe { ioctl::set_lsb_first(fd, &lsb_first_value) })
+    )?;
     Ok(())
 }
 
+/// Read the word size, where zero stands for 8 bits
 pub fn get_bits_per_word(fd: RawFd) -> io::Result<u8> {
     let mut bits_per_word: u8 = 0;
-    from_nix_result(unsafe { ioctl::get_bits_per_word(fd, &mut bits_per_word) })?;
+    traced!(
+        "SPI_IOC_RD_BITS_PER_WORD",
+        fd,
+        from_nix_result(unsafe { ioctl::get_bits_per_word(fd, &mut bits_per_word) })
+    )?;
     Ok(bits_per_word)
 }
 
+/// Set the word size, failing with `EINVAL` if the controller does
+/// not support it
 pub fn set_bits_per_word(fd: RawFd, bits_per_word: u8) -> io::Result<()> {
-    from_nix_result(unsafe { ioctl::set_bits_per_word(fd, &bits_per_word) })?;
+    traced!(
+        "SPI_IOC_WR_BITS_PER_WORD",
+        fd,
+        bits_per_word,
+        from_nix_result(unsafe { ioctl::set_bits_per_word(fd, &bits_per_word) })
+    )?;
     Ok(())
 }
 
+/// Read the default maximum clock speed, in Hz
 pub fn get_max_speed_hz(fd: RawFd) -> io::Result<u32> {
     let mut max_speed_hz: u32 = 0;
-    from_nix_result(unsafe { ioctl::get_max_speed_hz(fd, &mut max_speed_hz) })?;
+    traced!(
+        "SPI_IOC_RD_MAX_SPEED_HZ",
+        fd,
+        from_nix_result(unsafe { ioctl::get_max_speed_hz(fd, &mut max_speed_hz) })
+    )?;
     Ok(max_speed_hz)
 }
 
+/// Set the default maximum clock speed, in Hz
 pub fn set_max_speed_hz(fd: RawFd, max_speed_hz: u32) -> io::Result<()> {
-    from_nix_result(unsafe { ioctl::set_max_speed_hz(fd, &max_speed_hz) })?;
+    traced!(
+        "SPI_IOC_WR_MAX_SPEED_HZ",
+        fd,
+        max_speed_hz,
+        from_nix_result(unsafe { ioctl::set_max_speed_hz(fd, &max_speed_hz) })
+    )?;
     Ok(())
 }
 
-pub fn transfer(fd: RawFd, transfer: &mut SpidevTransfer) -> io::Result<()> {
+/// Perform a single transfer, returning the number of bytes moved
+pub fn transfer(fd: RawFd, transfer: &mut SpidevTransfer) -> io::Result<usize> {
     // The kernel will directly modify the rx_buf of the SpidevTransfer
     // rx_buf if present, so there is no need to do any additional work
-    from_nix_result(unsafe { ioctl::spidev_transfer(fd, transfer) })?;
+    let count = traced!(
+        "SPI_IOC_MESSAGE",
+        fd,
+        format_args!("1 transfer, {} bytes", transfer.len()),
+        from_nix_result(unsafe { ioctl::spidev_transfer(fd, transfer) })
+    )?;
+    #[cfg(feature = "log-payloads")]
+    log_payloads(std::slice::from_mut(transfer));
+    Ok(count as usize)
+}
+
+/// Perform a message of several transfers, returning the total number
+/// of bytes moved
+pub fn transfer_multiple(fd: RawFd, transfers: &mut [SpidevTransfer]) -> io::Result<usize> {
+    if transfers.len() > SPI_IOC_MESSAGE_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} transfers exceed the limit of {} transfers per message",
+                transfers.len(),
+                SPI_IOC_MESSAGE_MAX
+            ),
+        ));
+    }
+    let count = traced!(
+        "SPI_IOC_MESSAGE",
+        fd,
+        format_args!(
+            "{} transfers, {} bytes",
+            transfers.len(),
+            transfers.iter().map(SpidevTransfer::len).sum::<usize>()
+        ),
+        from_nix_result(unsafe { ioctl::spidev_transfer_buf(fd, transfers) })
+    )?;
+    #[cfg(feature = "log-payloads")]
+    log_payloads(transfers);
+    Ok(count as usize)
+}
+
+/// Submit a message of several transfers with an explicit request code
+///
+/// This is [`transfer_multiple`] with `op` in place of
+/// `SPI_IOC_MESSAGE(transfers.len())`, for kernels whose message ioctl
+/// differs from the one this crate knows, e.g. during development of
+/// new variants.  [`spi_ioc_message`] computes the standard code.
+///
+/// # Safety
+///
+/// `op` must be an ioctl which accesses at most `transfers.len()`
+/// transfers through the pointer it is passed and the buffers they
+/// point at, within the bounds of those buffers, and does nothing else
+/// unsound.
+pub unsafe fn transfer_multiple_with_op(
+    fd: RawFd,
+    transfers: &mut [SpidevTransfer],
+    op: c_ulong,
+) -> io::Result<usize> {
+    let count = traced!(
+        "SPI_IOC_MESSAGE",
+        fd,
+        format_args!(
+            "{} transfers, {} bytes, op {:#x}",
+            transfers.len(),
+            transfers.iter().map(SpidevTransfer::len).sum::<usize>(),
+            op
+        ),
+        from_nix_result(Errno::result(libc::ioctl(
+            fd,
+            op as _,
+            transfers.as_mut_ptr()
+        )))
+    )?;
+    #[cfg(feature = "log-payloads")]
+    log_payloads(transfers);
+    Ok(count as usize)
+}
+
+/// Trace the data sent and received by a completed message
+#[cfg(feature = "log-payloads")]
+fn log_payloads(transfers: &mut [SpidevTransfer]) {
+    for (index, transfer) in transfers.iter_mut().enumerate() {
+        if let Some(tx) = transfer.tx_slice() {
+            log::trace!("  transfer {} tx: {:02x?}", index, tx);
+        }
+        if let Some(rx) = transfer.rx_slice_mut() {
+            log::trace!("  transfer {} rx: {:02x?}", index, rx);
+        }
+    }
+}
+
+/// Call `f` until it completes with anything but `EINTR`
+///
+/// Long transfers may be interrupted by a signal before the kernel
+/// starts them, in which case they can simply be issued again.  The
+/// `Spidev` wrapper applies this to every ioctl by default.
+pub fn retry_on_eintr<T, F: FnMut() -> io::Result<T>>(mut f: F) -> io::Result<T> {
+    loop {
+        match f() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+/// Issue an arbitrary ioctl which reads a `T` from the device
+///
+/// # Safety
+///
+/// `op` must be an ioctl which fully initializes a value of type `T`
+/// through the pointer it is passed, and does nothing else unsound.
+pub unsafe fn ioctl_read<T>(fd: RawFd, op: c_ulong) -> io::Result<T> {
+    let mut value = MaybeUninit::<T>::uninit();
+    from_nix_result(Errno::result(libc::ioctl(fd, op as _, value.as_mut_ptr())))?;
+    Ok(value.assume_init())
+}
+
+/// Issue an arbitrary ioctl which writes a `T` to the device
+///
+/// # Safety
+///
+/// `op` must be an ioctl which only reads a value of type `T` through
+/// the pointer it is passed, and does nothing else unsound.
+pub unsafe fn ioctl_write<T>(fd: RawFd, op: c_ulong, value: &T) -> io::Result<()> {
+    from_nix_result(Errno::result(libc::ioctl(fd, op as _, value as *const T)))?;
     Ok(())
 }
 
-pub fn transfer_multiple(fd: RawFd, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
-    from_nix_result(unsafe { ioctl::spidev_transfer_buf(fd, transfers) })?;
+/// Issue an arbitrary ioctl which both reads and updates a `T`
+///
+/// # Safety
+///
+/// `op` must be an ioctl which accesses at most one value of type `T`
+/// through the pointer it is passed, leaves it valid, and does
+/// nothing else unsound.
+pub unsafe fn ioctl_read_write<T>(fd: RawFd, op: c_ulong, value: &mut T) -> io::Result<()> {
+    from_nix_result(Errno::result(libc::ioctl(fd, op as _, value as *mut T)))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_try_read_write_length_mismatch() {
+        let tx_buf = [0u8; 3];
+        let mut rx_buf = [0u8; 4];
+        let err = SpidevTransfer::try_read_write(&tx_buf, &mut rx_buf).unwrap_err();
+        assert_eq!(
+            err,
+            TransferError::LengthMismatch {
+                tx_len: 3,
+                rx_len: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_cs_behavior() {
+        let transfer = SpidevTransfer::delay(0);
+        assert_eq!(transfer.cs_change, 0);
+        let transfer = transfer.with_cs_behavior(CsBehavior::Deselect);
+        assert_eq!(transfer.cs_change, 1);
+        let transfer = transfer.with_cs_behavior(CsBehavior::KeepSelected);
+        assert_eq!(transfer.cs_change, 0);
+        let transfer = transfer.with_final_cs_behavior(CsBehavior::KeepSelected);
+        assert_eq!(transfer.cs_change, 1);
+        let transfer = transfer.with_final_cs_behavior(CsBehavior::Deselect);
+        assert_eq!(transfer.cs_change, 0);
+    }
+
+    #[test]
+    fn test_try_read_write() {
+        let tx_buf = [0u8; 3];
+        let mut rx_buf = [0u8; 3];
+        let transfer = SpidevTransfer::try_read_write(&tx_buf, &mut rx_buf).unwrap();
+        assert_eq!(transfer.len(), 3);
+    }
+
+    #[test]
+    fn test_with_speed_hz() {
+        let tx_buf = [0u8; 2];
+        let transfer = SpidevTransfer::write(&tx_buf).with_speed_hz(100_000);
+        assert_eq!(transfer.speed_hz, 100_000);
+        assert_eq!(SpidevTransfer::write(&tx_buf).speed_hz, 0);
+    }
+
+    #[test]
+    fn test_read_with_fill() {
+        let mut rx_buf = [0u8; 4];
+        let transfer = SpidevTransfer::read_with_fill(&mut rx_buf, 0xff);
+        assert_eq!(transfer.tx_slice(), Some(&[0xff; 4][..]));
+        assert_eq!(transfer.tx_buf, transfer.rx_buf);
+    }
+
+    #[test]
+    fn test_from_raw_parts() {
+        let tx_buf = [1u8, 2, 3];
+        let mut rx_buf = [0u8; 3];
+        let transfer =
+            unsafe { SpidevTransfer::from_raw_parts(tx_buf.as_ptr(), rx_buf.as_mut_ptr(), 3) };
+        assert_eq!(transfer.len(), 3);
+        assert_eq!(transfer.tx_slice(), Some(&tx_buf[..]));
+
+        let transfer =
+            unsafe { SpidevTransfer::from_raw_parts(std::ptr::null(), rx_buf.as_mut_ptr(), 3) };
+        assert_eq!(transfer.tx_slice(), None);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let tx_buf = [1u8, 2, 3, 4, 5];
+        let mut transfer = SpidevTransfer::write(&tx_buf).with_speed_hz(1000);
+        transfer.delay_usecs = 10;
+        let pieces = transfer.chunks(2);
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(pieces[0].tx_slice(), Some(&tx_buf[..2]));
+        assert_eq!(pieces[2].tx_slice(), Some(&tx_buf[4..]));
+        assert!(pieces.iter().all(|piece| piece.speed_hz == 1000));
+        assert_eq!(pieces[1].delay_usecs, 0);
+        assert_eq!(pieces[2].delay_usecs, 10);
+
+        let mut rx_buf = [0u8; 4];
+        let transfer = SpidevTransfer::read(&mut rx_buf);
+        let mut pieces = transfer.chunks(4);
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].tx_slice(), None);
+        assert_eq!(pieces[0].rx_slice_mut().map(|buf| buf.len()), Some(4));
+    }
+
+    #[test]
+    fn test_transfer_layout() {
+        let transfer = MaybeUninit::<SpidevTransfer>::uninit();
+        let base = transfer.as_ptr() as usize;
+        let offset = |field: *const u8| field as usize - base;
+        // Offsets from struct spi_ioc_transfer in linux/spi/spidev.h
+        unsafe {
+            let ptr = transfer.as_ptr();
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).tx_buf).cast()), 0);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).rx_buf).cast()), 8);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).len).cast()), 16);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).speed_hz).cast()), 20);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).delay_usecs).cast()), 24);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).bits_per_word).cast()), 26);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).cs_change).cast()), 27);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).tx_nbits).cast()), 28);
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).rx_nbits).cast()), 29);
+            assert_eq!(
+                offset(std::ptr::addr_of!((*ptr).word_delay_usecs).cast()),
+                30
+            );
+            assert_eq!(offset(std::ptr::addr_of!((*ptr).pad).cast()), 31);
+        }
+    }
+
+    #[test]
+    fn test_nbits() {
+        let tx_buf = [0u8; 4];
+        let transfer = SpidevTransfer::write(&tx_buf).with_tx_nbits(4).unwrap();
+        assert_eq!(transfer.tx_nbits(), 4);
+        assert_eq!(transfer.rx_nbits(), 0);
+        let transfer = SpidevTransfer::write(&tx_buf).with_rx_nbits(8).unwrap();
+        assert_eq!(transfer.rx_nbits(), 8);
+        assert_eq!(
+            SpidevTransfer::write(&tx_buf).with_rx_nbits(3).unwrap_err(),
+            TransferError::InvalidNbits { nbits: 3 }
+        );
+        assert_eq!(size_of::<SpidevTransfer>(), 32);
+    }
+
+    #[test]
+    fn test_delay_duration() {
+        let transfer = SpidevTransfer::delay_duration(Duration::from_millis(5)).unwrap();
+        assert_eq!(transfer.delay_usecs, 5000);
+        assert!(transfer.is_empty());
+
+        let delay = Duration::from_micros(u16::MAX as u64 + 1);
+        assert_eq!(
+            SpidevTransfer::delay_duration(delay).unwrap_err(),
+            TransferError::DelayTooLong { delay }
+        );
+    }
+
+    #[test]
+    fn test_retry_on_eintr() {
+        let mut attempts = 0;
+        let result = retry_on_eintr(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::Error::from_raw_os_error(libc::EINTR))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let result: io::Result<()> =
+            retry_on_eintr(|| Err(io::Error::from_raw_os_error(libc::EIO)));
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+    }
+
+    #[test]
+    fn test_transfer_multiple_too_many() {
+        // 32-byte transfers in a 14-bit size field, 13 bits on some
+        // architectures
+        assert_eq!(SPI_IOC_MESSAGE_MAX, ((1 << SIZEBITS) - 1) / 32);
+        if SIZEBITS == 14 {
+            assert_eq!(SPI_IOC_MESSAGE_MAX, 511);
+        }
+        let mut transfers: Vec<_> = (0..600).map(|_| SpidevTransfer::delay(0)).collect();
+        let err = transfer_multiple(-1, &mut transfers).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("600 transfers"));
+    }
+
+    #[test]
+    fn test_spi_ioc_message() {
+        // _IOW('k', 0, struct spi_ioc_transfer[n])
+        for n in [1, 2, SPI_IOC_MESSAGE_MAX] {
+            let size = n * size_of::<SpidevTransfer>();
+            let expected = request_code_write!(b'k', 0, size) as c_ulong;
+            assert_eq!(spi_ioc_message(n), Some(expected));
+        }
+        assert_eq!(spi_ioc_message(SPI_IOC_MESSAGE_MAX + 1), None);
+
+        // values from the kernel headers for the asm-generic layout
+        #[cfg(not(any(
+            target_arch = "powerpc",
+            target_arch = "powerpc64",
+            target_arch = "mips",
+            target_arch = "mips64",
+            target_arch = "sparc64"
+        )))]
+        {
+            assert_eq!(spi_ioc_message(1), Some(0x4020_6b00));
+            assert_eq!(spi_ioc_message(2), Some(0x4040_6b00));
+        }
+    }
+
+    #[test]
+    fn test_transfer_len() {
+        let tx_buf = [0u8; 5];
+        assert_eq!(SpidevTransfer::write(&tx_buf).len(), 5);
+        assert!(!SpidevTransfer::write(&tx_buf).is_empty());
+        assert!(SpidevTransfer::delay(10).is_empty());
+        assert!(SpidevTransfer::cs_toggle().is_empty());
+    }
+}
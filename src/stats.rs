@@ -0,0 +1,77 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::Duration;
+
+/// Traffic counters kept by a `Spidev` with the `metrics` feature
+///
+/// Each transfer message and each `read`/`write` system call counts
+/// as one transfer.  Failed calls only increment `errors`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpidevStats {
+    /// Bytes sent from a transmit buffer
+    pub bytes_tx: u64,
+    /// Bytes stored into a receive buffer
+    pub bytes_rx: u64,
+    /// Number of successful system calls moving data
+    pub transfer_count: u64,
+    /// Number of failed system calls moving data
+    pub errors: u64,
+    /// Time spent waiting for those system calls
+    pub kernel_time: Duration,
+}
+
+impl SpidevStats {
+    /// Account for one system call
+    pub(crate) fn record(&mut self, bytes_tx: usize, bytes_rx: usize, elapsed: Duration, ok: bool) {
+        self.kernel_time += elapsed;
+        if ok {
+            self.bytes_tx += bytes_tx as u64;
+            self.bytes_rx += bytes_rx as u64;
+            self.transfer_count += 1;
+        } else {
+            self.errors += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Spidev;
+    use std::io::prelude::*;
+
+    #[test]
+    fn test_record() {
+        let mut stats = SpidevStats::default();
+        stats.record(4, 2, Duration::from_micros(10), true);
+        stats.record(8, 8, Duration::from_micros(5), false);
+        assert_eq!(
+            stats,
+            SpidevStats {
+                bytes_tx: 4,
+                bytes_rx: 2,
+                transfer_count: 1,
+                errors: 1,
+                kernel_time: Duration::from_micros(15),
+            }
+        );
+    }
+
+    #[test]
+    fn test_write_counted() {
+        let mut spi = Spidev::open("/dev/null").unwrap();
+        spi.write_all(&[1, 2, 3]).unwrap();
+        let stats = spi.stats();
+        assert_eq!(stats.bytes_tx, 3);
+        assert_eq!(stats.transfer_count, 1);
+
+        spi.reset_stats();
+        assert_eq!(spi.stats(), SpidevStats::default());
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::SpiModeFlags;
+use nix::sys::stat::{major, minor};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Capabilities of a device and its controller as reported by sysfs
+///
+/// Each field is `None` when the kernel does not expose the
+/// corresponding attribute, which is common on systems that are not
+/// described by a device tree.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpiCapabilities {
+    /// Number of chip selects provided by the controller
+    pub num_chipselects: Option<u32>,
+    /// Maximum clock speed declared for the device, in Hz
+    pub max_speed_hz: Option<u32>,
+    /// Mode bits declared for the device, including the
+    /// Dual/Quad/Octal bits derived from the bus widths
+    pub mode_bits: Option<SpiModeFlags>,
+}
+
+/// Size of the spidev transfer buffer used when sysfs does not report one
+pub(crate) const DEFAULT_BUFSIZ: usize = 4096;
+
+/// Maximum number of bytes in a single spidev message
+///
+/// This is the `bufsiz` parameter of the spidev module, which can only
+/// be changed when the module is loaded.
+pub(crate) fn bufsiz() -> usize {
+    read_usize(Path::new("/sys/module/spidev/parameters/bufsiz")).unwrap_or(DEFAULT_BUFSIZ)
+}
+
+/// Release of the running kernel as `(major, minor)`
+pub(crate) fn kernel_version() -> Option<(u32, u32)> {
+    parse_kernel_version(&fs::read_to_string("/proc/sys/kernel/osrelease").ok()?)
+}
+
+/// Parse the leading `major.minor` of a release such as `5.10.0-rpi`
+fn parse_kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.trim().split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Locate the sysfs directory of the SPI device behind an open spidev
+///
+/// The character device numbers are resolved through `/sys/dev/char`,
+/// which yields something like `/sys/devices/.../spi_master/spi0/spi0.0`.
+pub(crate) fn device_dir(devfile: &File) -> io::Result<PathBuf> {
+    let rdev = devfile.metadata()?.rdev();
+    fs::canonicalize(format!(
+        "/sys/dev/char/{}:{}/device",
+        major(rdev),
+        minor(rdev)
+    ))
+}
+
+/// Read the capabilities of the SPI device rooted at `device_dir`
+///
+/// The parent of the device directory is the controller.
+pub(crate) fn capabilities(device_dir: &Path) -> SpiCapabilities {
+    let device_node = device_dir.join("of_node");
+    let controller_node = device_dir.parent().map(|dir| dir.join("of_node"));
+
+    SpiCapabilities {
+        num_chipselects: controller_node.and_then(|node| read_of_u32(&node.join("num-cs"))),
+        max_speed_hz: read_of_u32(&device_node.join("spi-max-frequency")),
+        mode_bits: read_of_mode(&device_node),
+    }
+}
+
+/// Attributes some controller drivers use to publish the achieved clock
+const EFFECTIVE_SPEED_ATTRIBUTES: &[&str] = &["actual_speed_hz", "speed_hz"];
+
+/// Read the clock rate actually achieved for the device at `device_dir`
+///
+/// The device directory is searched first, then its controller.
+pub(crate) fn effective_speed_hz(device_dir: &Path) -> Option<u32> {
+    let dirs = std::iter::once(device_dir).chain(device_dir.parent());
+    for dir in dirs {
+        for attribute in EFFECTIVE_SPEED_ATTRIBUTES {
+            if let Some(speed) = read_decimal(&dir.join(attribute)) {
+                return Some(speed);
+            }
+        }
+    }
+    None
+}
+
+/// Read a sysfs attribute holding a decimal number
+fn read_decimal(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Read a sysfs attribute holding a decimal size
+fn read_usize(path: &Path) -> Option<usize> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Read a single cell device tree property, stored big-endian
+fn read_of_u32(path: &Path) -> Option<u32> {
+    let bytes = fs::read(path).ok()?;
+    let cell: [u8; 4] = bytes.get(..4)?.try_into().ok()?;
+    Some(u32::from_be_bytes(cell))
+}
+
+/// Derive the mode bits from the device tree properties of a device
+fn read_of_mode(node: &Path) -> Option<SpiModeFlags> {
+    if !node.is_dir() {
+        return None;
+    }
+
+    let mut mode = SpiModeFlags::empty();
+    let flags = [
+        ("spi-cpha", SpiModeFlags::SPI_CPHA),
+        ("spi-cpol", SpiModeFlags::SPI_CPOL),
+        ("spi-cs-high", SpiModeFlags::SPI_CS_HIGH),
+        ("spi-lsb-first", SpiModeFlags::SPI_LSB_FIRST),
+        ("spi-3wire", SpiModeFlags::SPI_3WIRE),
+        ("spi-cs-word", SpiModeFlags::SPI_CS_WORD),
+    ];
+    for (property, flag) in flags.iter() {
+        if node.join(property).exists() {
+            mode |= *flag;
+        }
+    }
+    match read_of_u32(&node.join("spi-tx-bus-width")) {
+        Some(2) => mode |= SpiModeFlags::SPI_TX_DUAL,
+        Some(4) => mode |= SpiModeFlags::SPI_TX_QUAD,
+        Some(8) => mode |= SpiModeFlags::SPI_TX_OCTAL,
+        _ => {}
+    }
+    match read_of_u32(&node.join("spi-rx-bus-width")) {
+        Some(2) => mode |= SpiModeFlags::SPI_RX_DUAL,
+        Some(4) => mode |= SpiModeFlags::SPI_RX_QUAD,
+        Some(8) => mode |= SpiModeFlags::SPI_RX_OCTAL,
+        _ => {}
+    }
+    Some(mode)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn test_capabilities_from_of_node() {
+        let root = scratch_dir("caps");
+        let device = root.join("spi0").join("spi0.0");
+        fs::create_dir_all(root.join("spi0/of_node")).unwrap();
+        fs::create_dir_all(device.join("of_node")).unwrap();
+        fs::write(root.join("spi0/of_node/num-cs"), 2u32.to_be_bytes()).unwrap();
+        fs::write(
+            device.join("of_node/spi-max-frequency"),
+            10_000_000u32.to_be_bytes(),
+        )
+        .unwrap();
+        fs::write(device.join("of_node/spi-cpha"), b"").unwrap();
+        fs::write(device.join("of_node/spi-rx-bus-width"), 4u32.to_be_bytes()).unwrap();
+
+        let caps = capabilities(&device);
+        assert_eq!(caps.num_chipselects, Some(2));
+        assert_eq!(caps.max_speed_hz, Some(10_000_000));
+        assert_eq!(
+            caps.mode_bits,
+            Some(SpiModeFlags::SPI_CPHA | SpiModeFlags::SPI_RX_QUAD)
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_effective_speed_hz() {
+        let root = scratch_dir("speed");
+        let device = root.join("spi0").join("spi0.0");
+        fs::create_dir_all(&device).unwrap();
+        assert_eq!(effective_speed_hz(&device), None);
+
+        fs::write(root.join("spi0/actual_speed_hz"), b"976562\n").unwrap();
+        assert_eq!(effective_speed_hz(&device), Some(976_562));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(parse_kernel_version("5.10.0-rpi\n"), Some((5, 10)));
+        assert_eq!(parse_kernel_version("4.18.0"), Some((4, 18)));
+        assert_eq!(parse_kernel_version("6.1-rc3"), Some((6, 1)));
+        assert_eq!(parse_kernel_version("unknown"), None);
+    }
+
+    #[test]
+    fn test_capabilities_missing_attributes() {
+        let root = scratch_dir("nocaps");
+        let device = root.join("spi1").join("spi1.0");
+        fs::create_dir_all(&device).unwrap();
+
+        assert_eq!(capabilities(&device), SpiCapabilities::default());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
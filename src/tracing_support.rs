@@ -0,0 +1,46 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Spans of the `tracing` crate around transfer messages
+//!
+//! Every message submitted by `Spidev::transfer` and
+//! `Spidev::transfer_multiple` (and the helpers built on them) runs
+//! inside a `spidev_message` span at debug level.  The span carries the
+//! file descriptor, the number of transfers and bytes, and once the
+//! ioctl returns its outcome and duration.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+use tracing::field::{self, Empty};
+use tracing::Span;
+
+/// Open the span for a message of `transfers` transfers
+pub(crate) fn message_span(fd: RawFd, transfers: usize, bytes: usize) -> Span {
+    tracing::debug_span!(
+        "spidev_message",
+        fd,
+        transfers,
+        bytes,
+        outcome = Empty,
+        duration_us = Empty,
+    )
+}
+
+/// Record how the ioctl of the message went
+pub(crate) fn record<T, E: fmt::Display>(span: &Span, result: &Result<T, E>, elapsed: Duration) {
+    match result {
+        Ok(_) => span.record("outcome", "ok"),
+        Err(err) => span.record("outcome", field::display(err)),
+    };
+    span.record(
+        "duration_us",
+        u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX),
+    );
+}
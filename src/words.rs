@@ -0,0 +1,143 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Order in which the bytes of a multi-byte value go on the wire
+///
+/// This is independent of `SpidevOptions::lsb_first`, which only
+/// selects the order of the bits within each word.  With 8 bits per
+/// word, `0x1234` sent `BigEndian` is the word `0x12` followed by the
+/// word `0x34`; `lsb_first` then decides whether each of those words is
+/// clocked out starting from bit 7 or bit 0, but never swaps the words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ByteOrder {
+    /// Most significant byte first, as used by most register maps
+    BigEndian,
+    /// Least significant byte first
+    LittleEndian,
+}
+
+impl ByteOrder {
+    /// Split `value` into bytes in wire order
+    pub fn pack_u16(self, value: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::BigEndian => value.to_be_bytes(),
+            ByteOrder::LittleEndian => value.to_le_bytes(),
+        }
+    }
+
+    /// Split every value into bytes in wire order, replacing `buf`
+    pub(crate) fn pack_u16_slice(self, values: &[u16], buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.reserve(values.len() * 2);
+        for &value in values {
+            buf.extend_from_slice(&self.pack_u16(value));
+        }
+    }
+
+    /// Assemble a value from bytes received in wire order
+    pub fn unpack_u16(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+            ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+        }
+    }
+}
+
+/// Bytes the kernel uses to store a word of `bits_per_word` bits
+///
+/// Words are right-justified in the smallest of 1, 2 or 4 bytes that
+/// holds them, in native byte order.
+pub(crate) fn word_size(bits_per_word: u8) -> usize {
+    match bits_per_word {
+        0..=8 => 1,
+        9..=16 => 2,
+        _ => 4,
+    }
+}
+
+/// Lay out `words` the way the kernel expects them in a transfer buffer
+pub(crate) fn pack_words(bits_per_word: u8, words: &[u32]) -> Vec<u8> {
+    let size = word_size(bits_per_word);
+    let mut buf = Vec::with_capacity(words.len() * size);
+    for &word in words {
+        match size {
+            1 => buf.push(word as u8),
+            2 => buf.extend_from_slice(&(word as u16).to_ne_bytes()),
+            _ => buf.extend_from_slice(&word.to_ne_bytes()),
+        }
+    }
+    buf
+}
+
+/// Extract words from a transfer buffer, dropping undefined high bits
+pub(crate) fn unpack_words(bits_per_word: u8, buf: &[u8], words: &mut [u32]) {
+    let size = word_size(bits_per_word);
+    let mask = u32::MAX >> (32 - u32::from(bits_per_word));
+    for (word, bytes) in words.iter_mut().zip(buf.chunks_exact(size)) {
+        let raw = match size {
+            1 => u32::from(bytes[0]),
+            2 => u32::from(u16::from_ne_bytes([bytes[0], bytes[1]])),
+            _ => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        };
+        *word = raw & mask;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pack_u16() {
+        assert_eq!(ByteOrder::BigEndian.pack_u16(0x1234), [0x12, 0x34]);
+        assert_eq!(ByteOrder::LittleEndian.pack_u16(0x1234), [0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_pack_u16_slice() {
+        let mut buf = vec![0xff];
+        ByteOrder::BigEndian.pack_u16_slice(&[0xf800, 0x07e0], &mut buf);
+        assert_eq!(buf, [0xf8, 0x00, 0x07, 0xe0]);
+        ByteOrder::LittleEndian.pack_u16_slice(&[0x001f], &mut buf);
+        assert_eq!(buf, [0x1f, 0x00]);
+    }
+
+    #[test]
+    fn test_unpack_u16_round_trip() {
+        for order in [ByteOrder::BigEndian, ByteOrder::LittleEndian].iter() {
+            assert_eq!(order.unpack_u16(order.pack_u16(0xbeef)), 0xbeef);
+        }
+        // Bit order within a byte is untouched, unlike with lsb_first
+        assert_eq!(ByteOrder::BigEndian.unpack_u16([0x80, 0x01]), 0x8001);
+    }
+
+    #[test]
+    fn test_word_size() {
+        assert_eq!(word_size(8), 1);
+        assert_eq!(word_size(9), 2);
+        assert_eq!(word_size(16), 2);
+        assert_eq!(word_size(17), 4);
+        assert_eq!(word_size(32), 4);
+    }
+
+    #[test]
+    fn test_pack_unpack_words() {
+        let packed = pack_words(9, &[0x1ff, 0x001]);
+        assert_eq!(packed.len(), 4);
+        assert_eq!(&packed[..2], &0x1ffu16.to_ne_bytes());
+
+        let mut words = [0; 2];
+        // bits above the word size are undefined on receive
+        unpack_words(9, &pack_words(16, &[0xffff, 0x0001]), &mut words);
+        assert_eq!(words, [0x1ff, 0x001]);
+
+        let mut words = [0; 1];
+        unpack_words(32, &pack_words(32, &[0xdead_beef]), &mut words);
+        assert_eq!(words, [0xdead_beef]);
+    }
+}